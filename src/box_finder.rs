@@ -1,38 +1,210 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
 
+use curve::{Curve, EdgeModel, FitStats};
 use image::*;
+use line_fit::*;
 use point_line::*;
+use sampler::Sampler;
+use threshold;
 use std::f64;
+use std::fmt;
+use std::str::FromStr;
+
+// The part of the path along an edge used to fit the line near the corner
+const PATH_LINE_START_FRAC : f64 = 0.60;
+const PATH_LINE_END_FRAC : f64 = 0.95;
+const PATH_LINE_MIN_POINTS : usize = 8;
+// Points further than this (pixels) from the line are ignored as outliers
+const PATH_LINE_THRESHOLD : f64 = 2.0;
+const PATH_LINE_ITERATIONS : usize = 200;
+
+// Spacing (pixels) of the samples taken across a line while following it
+const TRACE_SAMPLE_STEP : f64 = 0.5;
+// The direction we follow a line in is taken from the last
+// TRACE_DIR_WINDOW points, once we've got more than TRACE_DIR_MIN
+const TRACE_DIR_WINDOW : usize = 20;
+const TRACE_DIR_MIN : usize = 5;
+// Give up combining edges into boxes if we've found more than this many
+const MAX_DISTINCT_EDGES : usize = 12;
+
+// Where along each border of the image we start scanning inwards for the box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SearchMode {
+  // From the middle of each border, only searching further if that
+  // doesn't find four different edges
+  Centre,
+  // From several points along each border, keeping the combination of
+  // edges that best forms a quadrilateral
+  Multi,
+}
 
-const PATH_LINE_BASE_FRAC : f64 = 0.90;
-const PATH_LINE_END_FRAC : f64 = 0.85;
-const PATH_LINE_SAMPLES : usize = 50;
+impl FromStr for SearchMode {
+  type Err = String;
 
-// Find the brightness at the edge pointed to by d from our current location
-fn edge_brightness(i: &Image, start: &Point, d: Direction) -> u8 {
-  
-  let p = match d {
-    Direction::Down => Point { x: start.x, y: i.get_size().y-1 },
-    Direction::Up   => Point { x: start.x, y: 0 },
-    Direction::Left => Point { x: 0,  y: start.y },
-    Direction::Right => Point { x: i.get_size().x-1, y: start.y },
-  };
+  fn from_str(s: &str) -> Result<SearchMode, String> {
+    match s {
+      "centre" | "center" => Ok(SearchMode::Centre),
+      "multi" => Ok(SearchMode::Multi),
+      _ => Err(format!("Unknown search mode '{}' (expected centre or multi)", s)),
+    }
+  }
+}
+
+// How edge_finder decides what's dark
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ThresholdMode {
+  // From the darkest and lightest pixels along each scan line
+  Scan,
+  // One level for the whole image using Otsu's method
+  Otsu,
+  // Sauvola's adaptive threshold, for unevenly lit images
+  Sauvola,
+}
+
+impl FromStr for ThresholdMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<ThresholdMode, String> {
+    match s {
+      "scan"    => Ok(ThresholdMode::Scan),
+      "otsu"    => Ok(ThresholdMode::Otsu),
+      "sauvola" => Ok(ThresholdMode::Sauvola),
+      _ => Err(format!("Unknown threshold mode '{}' (expected scan, otsu or sauvola)", s)),
+    }
+  }
+}
+
+// Sauvola's window is this fraction of the smaller image dimension, but
+// at least SAUVOLA_MIN_WINDOW so that it's much wider than a line
+const SAUVOLA_WINDOW_FRAC : f64 = 0.05;
+const SAUVOLA_MIN_WINDOW : usize = 15;
+const SAUVOLA_K : f64 = 0.2;
+
+// Positions along each border (as a fraction of its length) that
+// SearchMode::Multi starts from; the middle first
+const MULTI_START_FRACS : [f64; 5] = [0.5, 0.35, 0.65, 0.2, 0.8];
+
+// Aspect ratios within this fraction of 1 are treated as square when
+// deciding which way up the box is
+const SQUARE_TOLERANCE : f64 = 0.05;
+
+// Tuning for box_finder
+#[derive(Copy, Clone, Debug)]
+pub struct FinderOptions {
+  pub search: SearchMode,
+  pub threshold: ThresholdMode,
+  // Expected width/height of the box, used to decide which way up it is
+  pub aspect: f64,
+}
+
+impl Default for FinderOptions {
+  fn default() -> FinderOptions {
+    FinderOptions { search: SearchMode::Centre, threshold: ThresholdMode::Scan, aspect: 2.0 }
+  }
+}
+
+// Why we failed to find an edge, and the point we'd got to
+#[derive(Copy, Clone, Debug)]
+pub enum Failure {
+  NoLine(Point),         // Ran off the image before finding the dark line
+  NoLight(Point),        // Ran off the image looking for the light past the line
+  FellOffEdge(Point),    // Ran off the image following the line to a corner
+  ShortEdge(Point, usize), // Traced too few points along the line to fit it
+  NoCorner(Point),       // The line doesn't meet the next edge's line
+  Endless(Point),        // Followed the line round a loop, or for too long, without a corner
+  TooFewEdges(usize),    // Couldn't find four different edges that make a box
+  NoSupportedBox(usize), // None of the lines found make a box that's on the edges
+}
 
-  i[p]
+// The edge is named by the direction edge_finder scanned in, i.e.
+// Right is the left hand edge of the box; None if the problem is with
+// the box as a whole
+#[derive(Copy, Clone, Debug)]
+pub struct BoxErr {
+  pub edge: Option<Direction>,
+  pub failure: Failure,
+}
+
+fn edge_name(d: Direction) -> &'static str {
+  match d {
+    Direction::Right => "left",
+    Direction::Left  => "right",
+    Direction::Down  => "top",
+    Direction::Up    => "bottom",
+  }
+}
+
+impl fmt::Display for BoxErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.edge {
+      Some(d) => write!(f, "Failed finding {} edge: ", edge_name(d))?,
+      None => write!(f, "Failed finding the box: ")?,
+    }
+    match self.failure {
+      Failure::NoLine(p) => write!(f, "ran off the image at {},{} looking for the line", p.x, p.y),
+      Failure::NoLight(p) => write!(f, "ran off the image at {},{} looking for light past the line", p.x, p.y),
+      Failure::FellOffEdge(p) => write!(f, "ran off the image at {},{} following the line", p.x, p.y),
+      Failure::ShortEdge(p, n) => write!(f, "only traced {} points along the line ending at {},{}", n, p.x, p.y),
+      Failure::NoCorner(p) => write!(f, "line near {},{} is parallel to the next edge", p.x, p.y),
+      Failure::Endless(p) => write!(f, "line through {},{} never reaches a corner", p.x, p.y),
+      Failure::TooFewEdges(n) => write!(f, "only found {} edges that join up", n),
+      Failure::NoSupportedBox(n) => write!(f, "no four of the {} lines found form a box on the edges", n),
+    }
+  }
 }
 
 // Hmm this might be tricky - my top edge brightness is so bridgt I'm seeing speckling in the line
 // the other edges we're good down to about 25 as black  - same problem on bottom edge
-// Contrast stretch the entire thing first?
+// Contrast stretch the entire thing first? (--detect-enhance does that now)
 // Idea: Get slope based on point at 15% and 5% from the end ,then do intersect of matching corners
 
+// Pixels either side of the dark run included when centring on a line
+const LINE_PROFILE_PAD : usize = 2;
+
+// Find the sub-pixel centre of the line crossing the run of dark pixels
+// from 'outer' to 'inner' (in direction 'across').  The profile across the
+// line is weighted by how much darker each pixel is than the light either
+// side of it, so a line blurred symmetrically across a pixel boundary
+// comes out on the boundary
+fn line_centre(i: &Image, outer: &Point, inner: &Point, across: Direction) -> Pointf {
+  let mut first = *outer;
+  for _ in 0..LINE_PROFILE_PAD {
+    if !first.step(across.clockwise().clockwise(), i, 1) { break; }
+  }
+  let mut last = *inner;
+  for _ in 0..LINE_PROFILE_PAD {
+    if !last.step(across, i, 1) { break; }
+  }
+
+  let light = i[first].max(i[last]) as f64;
+  let mut cur = first;
+  let mut offset = 0.0;
+  let mut total_weight = 0.0;
+  let mut total = 0.0;
+  loop {
+    let weight = (light - i[cur] as f64).max(0.0);
+    total += weight * offset;
+    total_weight += weight;
+    if cur.x == last.x && cur.y == last.y { break; }
+    if !cur.step(across, i, 1) { break; }
+    offset += 1.0;
+  }
+
+  let centre = Pointf::from(first);
+  if total_weight == 0.0 {
+    // Flat profile; just take the middle of the run
+    return centre.offset(across, first.distance(&last) / 2.0);
+  }
+  centre.offset(across, total / total_weight)
+}
+
 // Moves until we hit a light point; returns the last dark point
 // (or the original if it was light)
-fn step_to_light(i: &Image, start: &Point, d: Direction, dark_to_light_threshold: u8) -> (Point,(u8,u8,usize)) {
+fn step_to_light(i: &Image, start: &Point, d: Direction, dark_to_light_threshold: u16) -> Result<(Point,(u16,u16,usize)), Failure> {
   let mut cur = *start;
   let mut res = cur;
-  let mut darkest = 255;
+  let mut darkest = i.get_maxval();
   let mut lightest = 0;
   let mut count = 0;
   let mut total : usize = 0;
@@ -45,133 +217,222 @@ fn step_to_light(i: &Image, start: &Point, d: Direction, dark_to_light_threshold
     if i[cur] < darkest { darkest = i[cur]; }
 
     res = cur;
-    if !cur.step(d, i, 1) { panic!("Ran off edge finding light"); }
+    if !cur.step(d, i, 1) { return Err(Failure::NoLight(cur)); }
   }
 
-  (res, (lightest, darkest, total/count))
+  Ok((res, (lightest, darkest, total/count.max(1))))
 }
 
-// We're given the bounds and middle of a line and expected to find where the end of it is in
-// direction 'd'.  Note the 'd' is a compass direction since we don't know the slope of the line
-fn follow_edge(i: &Image, d: Direction, dark_to_light_threshold: u8, line_width: f64,
-              inner_start: &Point,
-              mid_start: &Point,
-              outer_start: &Point) -> Vec<Point> {
-  // A history of our mid points, we'll use it to synthesize a line at the end
-  let mut history = Vec::new();
+// What we found looking across the line we're following
+enum Section {
+  Blank,          // Nothing dark near where we expected the line
+  Corner,         // A dark run too wide to be our line; we've hit the next edge
+  Line(f64),      // The line, centred at this offset across from where we looked
+}
 
-  let mut cur_inner = *inner_start;
-  let mut cur_mid =   *mid_start;
-  let mut cur_outer = *outer_start;
+// Look across the line at 'p' along the unit vector 'across', up to 'reach'
+// either side, for a dark run near 'p'.  The centre is found the same way
+// as line_centre, but by sampling between pixels so that it works at any angle
+fn cross_section(i: &Image, p: Pointf, across: Pointf, reach: f64, line_width: f64,
+                 dark_to_light_threshold: u16) -> Section {
+  let steps = (reach / TRACE_SAMPLE_STEP) as isize;
+  let samples : Vec<f64> = (-steps..(steps + 1))
+      .map(|k| Sampler::Bilinear.sample(i, p.along(across, k as f64 * TRACE_SAMPLE_STEP), 0) as f64)
+      .collect();
+  let centre = steps as usize;
+  let dark = |k: usize| samples[k] <= dark_to_light_threshold as f64;
+
+  // The nearest dark sample to where we expected the line
+  let search = ((line_width / TRACE_SAMPLE_STEP) as usize).min(centre);
+  let start = match (0..(search + 1)).flat_map(|o| vec![centre - o, centre + o])
+                                     .find(|&k| dark(k)) {
+    Some(k) => k,
+    None => return Section::Blank,
+  };
+  let mut lo = start;
+  while lo > 0 && dark(lo - 1) { lo -= 1; }
+  let mut hi = start;
+  while hi < samples.len() - 1 && dark(hi + 1) { hi += 1; }
+
+  if lo == 0 || hi == samples.len() - 1 ||
+     (hi - lo) as f64 * TRACE_SAMPLE_STEP > line_width * 3.0 {
+    return Section::Corner;
+  }
 
-  let mut found_point = cur_mid;
-  let mut found;
+  let pad = (LINE_PROFILE_PAD as f64 / TRACE_SAMPLE_STEP) as usize;
+  let first = lo.saturating_sub(pad);
+  let last = (hi + pad).min(samples.len() - 1);
+  let light = samples[first].max(samples[last]);
+  let mut total = 0.0;
+  let mut total_weight = 0.0;
+  for (k, v) in samples.iter().enumerate().take(last + 1).skip(first) {
+    let weight = (light - v).max(0.0);
+    total += weight * k as f64;
+    total_weight += weight;
+  }
+  let centre_k = if total_weight == 0.0 { (lo + hi) as f64 / 2.0 } else { total / total_weight };
+  Section::Line((centre_k - centre as f64) * TRACE_SAMPLE_STEP)
+}
 
-  println!("find_corner: {:?}/{:?}/{:?} going {:?}", inner_start, mid_start, outer_start, d);
-  loop {
-    history.push(cur_mid);
-    found = false;
-    if !cur_inner.step(d, i, 1) ||
-       !cur_mid.step(d, i, 1) ||
-       !cur_outer.step(d, i, 1) {
-      panic!("Fell off edge {:?}/{:?}/{:?}", cur_inner, cur_mid, cur_outer);
-    };
+fn inside(p: &Pointf, size: Point) -> bool {
+  p.x >= 0.0 && p.y >= 0.0 && p.x <= (size.x - 1) as f64 && p.y <= (size.y - 1) as f64
+}
+
+// Count the dark pixels after 'start' in direction 'd', up to 'limit'
+fn dark_run(i: &Image, start: &Point, d: Direction, threshold: u16, limit: usize) -> usize {
+  let mut cur = *start;
+  let mut count = 0;
+  while count < limit && cur.step(d, i, 1) && i[cur] <= threshold {
+    count += 1;
+  }
+  count
+}
 
-    if i[cur_mid] <= dark_to_light_threshold {
-      found_point = cur_mid;
-      found = true;
+// We're given the middle of a line and expected to find where the end of it
+// is, setting off in the direction 'start_dir'.  We steer along the line
+// using the direction of the last few points we've found, so the line can
+// be at any angle and a little curved
+fn follow_edge(i: &Image, start_dir: Pointf, dark_to_light_threshold: u16, line_width: f64,
+              centre_start: &Pointf) -> Result<Vec<Pointf>, Failure> {
+  // A history of our (sub-pixel) mid points, we'll use it to synthesize a line at the end
+  let mut history : Vec<Pointf> = Vec::new();
+  let size = i.get_size();
+  let reach = line_width * 3.0 + LINE_PROFILE_PAD as f64 + 2.0;
+  // A closed line (a ring, or a box with rounded corners) never meets
+  // another edge; we'd go round it forever, so give up once we've come
+  // back to where we started or gone further than round the whole image
+  let max_steps = 2 * (size.x + size.y);
+  let mut left_start = false;
+
+  let mut dir = start_dir;
+  let mut cur = *centre_start;
+
+  diag!("find_corner: {:?} going {:?}", centre_start, start_dir);
+  loop {
+    history.push(cur);
+    let n = history.len();
+    let from_start = cur.distance(centre_start);
+    if from_start > reach * 2.0 {
+      left_start = true;
+    } else if left_start && from_start < reach {
+      return Err(Failure::Endless(Point::from(*centre_start)));
     }
-    if !found && i[cur_outer] <= dark_to_light_threshold {
-      found_point = cur_outer;
-      found = true;
+    if n > max_steps {
+      return Err(Failure::Endless(Point::from(*centre_start)));
     }
-    if !found && i[cur_inner] <= dark_to_light_threshold {
-      found_point = cur_inner;
-      found = true;
+    if n > TRACE_DIR_MIN {
+      let from = history[n - 1 - (n - 1).min(TRACE_DIR_WINDOW)];
+      if let Some(new_dir) = from.unit_to(&cur) {
+        dir = new_dir;
+      }
     }
-    if !found {
-      println!("find_corner: Hit blank at {:?}/{:?}/{:?}", cur_outer,cur_mid,cur_inner);
-    } else {
-      // Find the middle of our current line
-      let (proto_outer,_) = step_to_light(i, &found_point, d.cntr_clockwise(), dark_to_light_threshold);
-      let (proto_inner,_) = step_to_light(i, &found_point, d.clockwise(), dark_to_light_threshold);
-      let distance = proto_outer.distance(&proto_inner);
 
-      if distance > line_width * 3.0 {
+    let next = cur.along(dir, 1.0);
+    if !inside(&next, size) {
+      return Err(Failure::FellOffEdge(Point::from(cur)));
+    }
+
+    let across = Pointf { x: -dir.y, y: dir.x };
+
+    // We reach the next edge at its near side, where looking across our
+    // line can run out of the side of it; so look half a line width ahead
+    // for the corner as well
+    let ahead = next.along(dir, line_width / 2.0);
+    let section = match cross_section(i, ahead, across, reach, line_width, dark_to_light_threshold) {
+      Section::Corner if inside(&ahead, size) => Section::Corner,
+      _ => cross_section(i, next, across, reach, line_width, dark_to_light_threshold),
+    };
+    match section {
+      Section::Blank => {
+        diag!("find_corner: Hit blank at {:?}", next);
+        cur = next;
+      },
+      Section::Corner => {
         // Looks like we've hit the corner because we've started running along a dark edge
-        println!("find_corner: Hit other edge at {:?}", found_point);
+        diag!("find_corner: Hit other edge at {:?}", next);
         break;
-      }
-
-      cur_outer = proto_outer;
-      cur_mid = proto_outer;
-      cur_mid.step(d.clockwise(), i, (distance/2.0) as usize);
-      cur_inner = proto_inner;
+      },
+      Section::Line(offset) => cur = next.along(across, offset),
     }
-    //println!("FSVG: <circle cx=\"{}\" cy=\"{}\" r=\"2px\" style=\"stroke:rgb(0,255,0);stroke-width=1\"",
-    //         cur_mid.x, cur_mid.y);
+    //eprintln!("FSVG: <circle cx=\"{}\" cy=\"{}\" r=\"2px\" style=\"stroke:rgb(0,255,0);stroke-width=1\"",
+    //         cur.x, cur.y);
   }
 
-  history
+  Ok(history)
 }
 
-// Given a vector of points along an edge, generate a line pointing in the direction of the end point
-fn gen_edge_vector(history: &Vec<Point>) -> Line {
-  // Synthesise a vector from the set of midpoints we've followed; the line is curved
+// Given a vector of points along an edge, fit a line to the part heading towards the end point
+fn gen_edge_vector(history: &[Pointf]) -> Result<LineFit, Failure> {
+  // Fit a line to the set of midpoints we've followed; the line is curved
   // so we use some near the end, but not right at the end because we tend to swing
   // off a bit as we approach the new edge (because we always recentre the midpoint on the middle of the
   // black range, and we also tend not to have clean corners)
-
-  // So we try PATH_LINE_SAMPLES out of each of two sets PATH_LINE_BASE/END_FRAC along
-  // and build a line from each pair; then figure out which is the best match to the points
+  // RANSAC throws out points that have been pulled off the line by speckle
   let path_len = history.len();
 
-  let outer_base = (path_len as f64 * PATH_LINE_BASE_FRAC) as usize;
-  let inner_base = (path_len as f64 * PATH_LINE_END_FRAC) as usize;
+  let start = (path_len as f64 * PATH_LINE_START_FRAC) as usize;
+  let end = (path_len as f64 * PATH_LINE_END_FRAC) as usize;
 
-  println!("path_len={} outer_base={} inner_base={}", path_len, outer_base, inner_base);
+  if end < start + PATH_LINE_MIN_POINTS {
+    return Err(Failure::ShortEdge(Point::from(history[path_len - 1]), path_len));
+  }
 
-  let mut best_score = f64::MAX;
-  let mut best_line = (Point { x:0, y:0 }, Point { x:0, y:0 });
-  for outer_index in 0..PATH_LINE_SAMPLES {
-    let outer_point = history[outer_base + outer_index];
+  let fit = match ransac(&history[start..end], PATH_LINE_THRESHOLD, PATH_LINE_ITERATIONS) {
+    Some(fit) => fit,
+    None => return Err(Failure::ShortEdge(Point::from(history[path_len - 1]), path_len)),
+  };
 
-    for inner_index in 0..PATH_LINE_SAMPLES {
-      let inner_point = history[inner_base + inner_index];
-      
-      let line = (outer_point, inner_point);
+  diag!("path_len={} fitted {}..{} residual={} confidence={}", path_len, start, end,
+            fit.residual, fit.confidence);
+  Ok(fit)
+}
 
-      // Build a score for this line by looping over all the
-      // (other) points and measuring their distance from the line
-      // so loop over both inner and outer points and get distance
-      let mut score = 0.0;
+// One edge of the box as found by edge_finder.  The ends are given
+// relative to the scan direction: 'ccw' is the end reached by turning
+// counter clockwise from it
+#[derive(Clone, Debug)]
+struct EdgeTrace {
+  line_ccw: LineFit,  // Line fitted near each end
+  line_cw: LineFit,
+  end_ccw: Pointf,    // The last point traced at each end
+  end_cw: Pointf,
+  mid: Pointf,        // Half way along the traced line
+  points: Vec<Pointf>, // Everything traced, from the ccw end to the cw end
+  width: f64,         // Line width as measured when we first crossed it
+  threshold: u16,     // Level used to tell the line from the paper
+  scan: Direction,    // The direction we were scanning in when we found it
+}
 
-      for score_index in 0..PATH_LINE_SAMPLES {
-        if score_index != outer_index {
-          score += history[outer_base + score_index].line_distance(&line);
-        }
-      } 
-      for score_index in 0..PATH_LINE_SAMPLES {
-        if score_index != inner_index {
-          score += history[inner_base + score_index].line_distance(&line);
-        }
-      } 
+impl EdgeTrace {
+  // Whether this is the same edge as 'other', i.e. it runs between the
+  // same corners; edges traced from different starting points should meet
+  // the same corners
+  fn same_edge(&self, other: &EdgeTrace) -> bool {
+    let tolerance = self.width.max(other.width) * 3.0 + 2.0;
+    (self.end_ccw.distance(&other.end_ccw) < tolerance && self.end_cw.distance(&other.end_cw) < tolerance) ||
+    (self.end_ccw.distance(&other.end_cw) < tolerance && self.end_cw.distance(&other.end_ccw) < tolerance)
+  }
 
-      if score < best_score {
-        best_line = line;
-        best_score = score;
-      }
+  // The end nearest 'p', with the line fitted towards it
+  fn nearest_end(&self, p: &Pointf) -> (Pointf, &LineFit, bool) {
+    if self.end_ccw.distance(p) < self.end_cw.distance(p) {
+      (self.end_ccw, &self.line_ccw, true)
+    } else {
+      (self.end_cw, &self.line_cw, false)
     }
-  } 
-  //println!("find_corner for {:?} point={:?} best_score={} best_line={:?}",
-  //         d, cur_mid, best_score, best_line);
-  best_line
+  }
 }
 
-fn edge_finder(i: &Image, start: &Point, d: Direction) -> (Line,Line,Point) {
+// 'level' is the threshold to use for the whole image, or None to work
+// it out from the scan line
+fn edge_finder(i: &Image, start: &Point, d: Direction, level: Option<u16>) -> Result<EdgeTrace, BoxErr> {
+  edge_finder_inner(i, start, d, level).map_err(|failure| BoxErr { edge: Some(d), failure })
+}
+
+fn edge_finder_inner(i: &Image, start: &Point, d: Direction, level: Option<u16>) -> Result<EdgeTrace, Failure> {
   let mut cur = *start;
 
-  println!("edge_finder: {:?} going {:?}", start, d);
+  diag!("edge_finder: {:?} going {:?}", start, d);
 
   // Step 1: Figure out thresholds
   // Search for the darkest/lightest on this scan line as a reference
@@ -179,7 +440,7 @@ fn edge_finder(i: &Image, start: &Point, d: Direction) -> (Line,Line,Point) {
   // TODO: The LTD is probably better off only being a scan of the centre
   // of the line
   let mut lightest = 0;
-  let mut darkest = 255;
+  let mut darkest = i.get_maxval();
   loop {
     if i[cur] < darkest { darkest = i[cur]; };
     if i[cur] > lightest { lightest = i[cur]; };
@@ -195,7 +456,7 @@ fn edge_finder(i: &Image, start: &Point, d: Direction) -> (Line,Line,Point) {
 
   // Step 1a: A local lightest/darkest - 10pixels around the current position
   let mut local_lightest = 0;
-  let mut local_darkest = 255;
+  let mut local_darkest = i.get_maxval();
   let mut clk_cur = *start;
   let mut cclk_cur = *start;
   for _ in 0..10 {
@@ -207,8 +468,16 @@ fn edge_finder(i: &Image, start: &Point, d: Direction) -> (Line,Line,Point) {
     if !cclk_cur.step(d.cntr_clockwise(), i, 1) { break; };
   }
 
-  let light_to_dark_threshold = (local_darkest as f64 * 0.6) as u8;
-  println!("Edge for direction {:?} darkest/lightest={}/{} local_d/l={}/{} ltd-threshold={}", d,
+  // With a threshold for the whole image we use it for everything;
+  // local_darkest is what's dark enough to be part of the line
+  let light_to_dark_threshold = match level {
+    Some(level) => {
+      local_darkest = level;
+      level
+    },
+    None => (local_darkest as f64 * 0.6) as u16,
+  };
+  diag!("Edge for direction {:?} darkest/lightest={}/{} local_d/l={}/{} ltd-threshold={}", d,
            darkest, lightest,
            local_darkest, local_lightest,
            light_to_dark_threshold);
@@ -216,71 +485,375 @@ fn edge_finder(i: &Image, start: &Point, d: Direction) -> (Line,Line,Point) {
   // Step2: Find the edge of the line
   'find_outer_edge: loop {
     if i[cur] < light_to_dark_threshold { break 'find_outer_edge; }
-    if !cur.step(d, i, 1) { panic!("Ran off edge finding outer edge"); }
+    if !cur.step(d, i, 1) { return Err(Failure::NoLine(cur)); }
   }
   let outer_edge_marker = cur;
 
-  let mut dark_to_light_threshold = darkest;
-  if i[cur] > dark_to_light_threshold {
+  let mut dark_to_light_threshold = level.unwrap_or(darkest);
+  if level.is_none() && i[cur] > dark_to_light_threshold {
     dark_to_light_threshold = i[cur]
   }
 
-  println!("dtl-threshold={} 1st point={}", dark_to_light_threshold, i[cur]);
+  diag!("dtl-threshold={} 1st point={}", dark_to_light_threshold, i[cur]);
 
   // Step3: Find the inner edge of the line
-  let (inner_edge_marker,_) = step_to_light(i, &cur, d, local_darkest);
+  let (inner_edge_marker,_) = step_to_light(i, &cur, d, local_darkest)?;
 
   // Step4: Find the midpoint of the edge
-  let line_width = inner_edge_marker.distance(&outer_edge_marker);
-  println!("Line width={}", line_width); 
-
-  let mut mid_point = outer_edge_marker;
-  mid_point.step(d, i, (line_width / 2.0) as usize);
+  let mid_point = line_centre(i, &outer_edge_marker, &inner_edge_marker, d);
+
+  // The line may cross our scan at an angle, making it look wider than it
+  // is; the run of dark along the line tells us how steep that angle is
+  let across = inner_edge_marker.distance(&outer_edge_marker) + 1.0;
+  let limit = (across * 8.0) as usize;
+  let mid_pixel = Point::from(Pointf { x: mid_point.x.round(), y: mid_point.y.round() });
+  let along = (dark_run(i, &mid_pixel, d.clockwise(), local_darkest, limit) +
+               dark_run(i, &mid_pixel, d.cntr_clockwise(), local_darkest, limit) + 1) as f64;
+  let line_width = across * along / (across * across + along * along).sqrt();
+  diag!("Line width={} (across={} along={})", line_width, across, along);
 
   // Step5: Follow the edges to the end/corners
-  let mut vec1 = follow_edge(i, d.cntr_clockwise(), dark_to_light_threshold, line_width, &inner_edge_marker, &mid_point, &outer_edge_marker);
-  let mut vec2 = follow_edge(i, d.clockwise(), dark_to_light_threshold, line_width, &inner_edge_marker, &mid_point, &outer_edge_marker);
+  let vec1 = follow_edge(i, d.cntr_clockwise().unit(), dark_to_light_threshold, line_width, &mid_point)?;
+  let vec2 = follow_edge(i, d.clockwise().unit(), dark_to_light_threshold, line_width, &mid_point)?;
+  let end_ccw = vec1[vec1.len() - 1];
+  let end_cw = vec2[vec2.len() - 1];
+
+  // Everything from the ccw end to the cw end
+  let mut points = vec1.clone();
+  points.reverse();
+  points.extend_from_slice(&vec2); // Hmm this dupes the start point?
 
   // Step6: Generate vectors pointing along the edge near the corner
   // the caller then combines the vectors from edges that touch to find
-  // the corner
-  let corner1 = gen_edge_vector(&vec1);
-  let corner2 = gen_edge_vector(&vec2);
+  // the corner.  If we crossed the line close to one corner, that half
+  // is too short to fit; fit its end along the whole trace instead,
+  // which is mostly the other half
+  let line_ccw = match gen_edge_vector(&vec1) {
+    Err(Failure::ShortEdge(..)) => {
+      let mut towards_ccw = points.clone();
+      towards_ccw.reverse();
+      gen_edge_vector(&towards_ccw)?
+    },
+    fit => fit?,
+  };
+  let line_cw = match gen_edge_vector(&vec2) {
+    Err(Failure::ShortEdge(..)) => gen_edge_vector(&points)?,
+    fit => fit?,
+  };
 
   // Step7: Find a midpoint on the edge to find as a curve point
-  vec1.reverse();
-  vec1.append(&mut vec2); // Hmm this dupes the start point?
-
-  let middle_point = vec1[vec1.len()/2];
-  (corner1, corner2, middle_point)
-}
-
-// Result is points going clockwise from top left with one midpoint on each line
-pub fn box_finder(i: &Image) -> (Point, Point, Point, Point, Point, Point, Point, Point) {
-  let image_size = i.get_size();
-  let left_middle = Point { x: 0, y: image_size.y/2 };
-  let right_middle = Point { x: image_size.x - 1, y: image_size.y/2 };
-  let top_middle = Point { x: image_size.x/2, y: 0 };
-  let bottom_middle = Point { x: image_size.x/2, y: image_size.y-1 };
-
-  let (left_edge_line_top, left_edge_line_bottom, left_edge_mid) =
-    edge_finder(i, &left_middle, Direction::Right);
-  let (right_edge_line_bottom, right_edge_line_top, right_edge_mid) =
-    edge_finder(i, &right_middle, Direction::Left);
-  let (top_edge_line_right, top_edge_line_left, top_edge_mid) =
-    edge_finder(i, &top_middle, Direction::Down);
-  let (bottom_edge_line_left, bottom_edge_line_right, bottom_edge_mid) =
-    edge_finder(i, &bottom_middle, Direction::Up);
-
-  let top_left = line_intersection(&left_edge_line_top, &top_edge_line_left);
-  let top_right = line_intersection(&right_edge_line_top, &top_edge_line_right);
-  let bottom_left = line_intersection(&left_edge_line_bottom, &bottom_edge_line_left);
-  let bottom_right = line_intersection(&right_edge_line_bottom, &bottom_edge_line_right);
-
-  // Going clockwise from top left
-  (top_left, top_edge_mid, top_right,
-             right_edge_mid, bottom_right,
-             bottom_edge_mid, bottom_left,
-   left_edge_mid)
+  let mid = points[points.len()/2];
+  Ok(EdgeTrace { line_ccw, line_cw, end_ccw, end_cw, mid, points, width: line_width,
+                 threshold: dark_to_light_threshold, scan: d })
+}
+
+// Intersect the lines from two edges that meet at a corner; 'edge' is the
+// edge to blame if they don't
+fn corner(l1: &LineFit, l2: &LineFit, edge: Direction) -> Result<Pointf, BoxErr> {
+  match l1.intersection(l2) {
+    Some(p) => Ok(p),
+    None => Err(BoxErr { edge: Some(edge), failure: Failure::NoCorner(Point::from(l1.point)) }),
+  }
+}
+
+// One side of a detected box, running clockwise round the box from one
+// corner to the next
+#[derive(Clone, Debug)]
+pub struct BoxEdge {
+  pub mid: Pointf,            // A point on the edge about half way along
+  pub points: Vec<Pointf>,    // Points found along the edge, in order
+  pub start_line: LineFit,    // Lines fitted near the start and end corners
+  pub end_line: LineFit,
+  pub width: Option<f64>,     // Width of the line, if the detector measured it
+  pub threshold: Option<u16>, // Level that told the line from the paper
+}
+
+// The box as found by box_finder or hough_finder, the right way up
+#[derive(Clone, Debug)]
+pub struct DetectedBox {
+  pub top_left: Pointf,
+  pub top_right: Pointf,
+  pub bottom_right: Pointf,
+  pub bottom_left: Pointf,
+  // Each runs clockwise, so the top goes left to right and the bottom
+  // right to left
+  pub top: BoxEdge,
+  pub right: BoxEdge,
+  pub bottom: BoxEdge,
+  pub left: BoxEdge,
+  // Angle of the top edge in degrees
+  pub rotation: f64,
+  // 0..1, how well the edges fitted and joined up
+  pub confidence: f64,
+}
+
+// The points along an edge are trimmed by this fraction at each end
+// before fitting a curve to them, since the traces swing off the line
+// as they run into the next edge
+const EDGE_FIT_END_FRAC : f64 = 0.05;
+
+// A curve of 'model' from 'start' to 'end' fitted to the points along
+// 'edge' (reversed if 'reverse'), falling back to the quadratic through
+// its midpoint if there aren't enough points.  The residuals are always
+// against all the points.
+fn edge_curve(start: Pointf, edge: &BoxEdge, end: Pointf, reverse: bool, model: EdgeModel) -> (Box<dyn Curve>, FitStats) {
+  let mut points = edge.points.clone();
+  if reverse {
+    points.reverse();
+  }
+  let trim = (points.len() as f64 * EDGE_FIT_END_FRAC) as usize;
+  let curve = model.fit(&points[trim..(points.len() - trim)], start, edge.mid, end)
+                   .unwrap_or_else(|| EdgeModel::Quadratic.fit(&[], start, edge.mid, end).unwrap());
+  let stats = FitStats::new(&*curve, &points);
+  (curve, stats)
 }
 
+impl DetectedBox {
+  // The edges as curves of the given model through their corners, with
+  // how well they fit the points along the edge; the top and bottom go
+  // left to right, the left and right top to bottom
+  pub fn top_curve(&self, model: EdgeModel) -> (Box<dyn Curve>, FitStats) {
+    edge_curve(self.top_left, &self.top, self.top_right, false, model)
+  }
+
+  pub fn bottom_curve(&self, model: EdgeModel) -> (Box<dyn Curve>, FitStats) {
+    edge_curve(self.bottom_left, &self.bottom, self.bottom_right, true, model)
+  }
+
+  pub fn left_curve(&self, model: EdgeModel) -> (Box<dyn Curve>, FitStats) {
+    edge_curve(self.top_left, &self.left, self.bottom_left, true, model)
+  }
+
+  pub fn right_curve(&self, model: EdgeModel) -> (Box<dyn Curve>, FitStats) {
+    edge_curve(self.top_right, &self.right, self.bottom_right, false, model)
+  }
+}
+
+// Where to start scanning in direction 'd' for each of the given
+// positions (fractions of the length) along the border
+fn start_points(size: Point, d: Direction, fracs: &[f64]) -> Vec<Point> {
+  fracs.iter().map(|f| {
+    let x = ((size.x as f64 * f) as usize).min(size.x - 1);
+    let y = ((size.y as f64 * f) as usize).min(size.y - 1);
+    match d {
+      Direction::Right => Point { x: 0, y },
+      Direction::Left  => Point { x: size.x - 1, y },
+      Direction::Down  => Point { x, y: 0 },
+      Direction::Up    => Point { x, y: size.y - 1 },
+    }
+  }).collect()
+}
+
+// Scan in from each border at each of 'fracs' and add any edges we
+// haven't already got to 'edges'.  Near 45 degrees the scans from two
+// borders can find the same edge, so which border we scanned from
+// doesn't tell us which edge of the box it is.  'first_err' gets the
+// first failure from a border where none of the scans found an edge, or
+// failing that the first failure of all.
+fn find_edges(i: &Image, fracs: &[f64], level: Option<u16>,
+              edges: &mut Vec<EdgeTrace>, first_err: &mut Option<BoxErr>) {
+  let mut any_err = None;
+  for &d in &[Direction::Right, Direction::Left, Direction::Down, Direction::Up] {
+    let mut found = false;
+    let mut border_err = None;
+    for start in start_points(i.get_size(), d, fracs) {
+      match edge_finder(i, &start, d, level) {
+        Ok(trace) => {
+          found = true;
+          if !edges.iter().any(|e| e.same_edge(&trace)) {
+            edges.push(trace);
+          }
+        },
+        Err(e) => {
+          diag!("{}", e);
+          border_err.get_or_insert(e);
+          any_err.get_or_insert(e);
+        },
+      }
+    }
+    if !found && first_err.is_none() {
+      *first_err = border_err;
+    }
+  }
+  if first_err.is_none() {
+    *first_err = any_err;
+  }
+}
+
+// A box made from four edges: the corners clockwise from an arbitrary one,
+// the edge following each corner, and how badly the edges fit together:
+// the distance between each corner and the ends of the two traces that
+// met there.  Edges from the same box should agree to within about a
+// line width.
+struct Quad {
+  corners: [Pointf; 4],
+  edges: Vec<BoxEdge>,
+  error: f64,
+}
+
+fn box_from_edges(edges: [&EdgeTrace; 4]) -> Result<Option<Quad>, BoxErr> {
+  // Put the edges in order clockwise around their middle
+  let cx = edges.iter().map(|e| e.mid.x).sum::<f64>() / 4.0;
+  let cy = edges.iter().map(|e| e.mid.y).sum::<f64>() / 4.0;
+  let mut edges = edges;
+  edges.sort_by(|a, b| {
+    let angle_a = (a.mid.y - cy).atan2(a.mid.x - cx);
+    let angle_b = (b.mid.y - cy).atan2(b.mid.x - cx);
+    angle_a.partial_cmp(&angle_b).unwrap()
+  });
+
+  // Corner k is where edge k meets edge k+1; each edge has to use
+  // different ends for its two corners
+  let mut corners = [Pointf { x: 0.0, y: 0.0 }; 4];
+  let mut toward_next = [false; 4];
+  let mut toward_prev = [false; 4];
+  let mut error = 0.0;
+  for k in 0..4 {
+    let e = edges[k];
+    let f = edges[(k + 1) % 4];
+    let (e_end, e_line, e_ccw) = e.nearest_end(&f.mid);
+    let (f_end, f_line, f_ccw) = f.nearest_end(&e.mid);
+    toward_next[k] = e_ccw;
+    toward_prev[(k + 1) % 4] = f_ccw;
+
+    corners[k] = corner(e_line, f_line, e.scan)?;
+    error += corners[k].distance(&e_end) + corners[k].distance(&f_end);
+  }
+  if (0..4).any(|k| toward_next[k] == toward_prev[k]) {
+    return Ok(None);
+  }
+
+  // Must be convex, turning clockwise at every corner
+  for k in 0..4 {
+    let a = corners[k];
+    let b = corners[(k + 1) % 4];
+    let c = corners[(k + 2) % 4];
+    if (b.x - a.x) * (c.y - b.y) - (b.y - a.y) * (c.x - b.x) <= 0.0 {
+      return Ok(None);
+    }
+  }
+
+  // Edge k+1 starts at corner k, at its ccw end if toward_prev
+  let box_edges = (0..4).map(|k| {
+    let f = edges[(k + 1) % 4];
+    let mut points = f.points.clone();
+    let (start_line, end_line) = if toward_prev[(k + 1) % 4] {
+      (f.line_ccw, f.line_cw)
+    } else {
+      points.reverse();
+      (f.line_cw, f.line_ccw)
+    };
+    BoxEdge { mid: f.mid, points, start_line, end_line,
+              width: Some(f.width), threshold: Some(f.threshold) }
+  }).collect();
+  Ok(Some(Quad { corners, edges: box_edges, error }))
+}
+
+// Pick which corner of the box is the logical top left: the edges that are
+// longer (or shorter, for a portrait 'aspect') are the top and bottom, and
+// the top is the one that runs left to right going clockwise.  A square
+// uses the edges nearest horizontal.  Returns the index of the top left
+// corner and the rotation of the top edge in degrees.
+fn top_left_corner(corners: &[Pointf; 4], aspect: f64) -> (usize, f64) {
+  let angle = |k: usize| {
+    let a = corners[k];
+    let b = corners[(k + 1) % 4];
+    (b.y - a.y).atan2(b.x - a.x)
+  };
+  let length = |k: usize| corners[k].distance(&corners[(k + 1) % 4]);
+
+  let even_long = length(0) + length(2) >= length(1) + length(3);
+  let even_flat = angle(0).sin().abs() + angle(2).sin().abs() <=
+                  angle(1).sin().abs() + angle(3).sin().abs();
+  let even_horizontal = if aspect > 1.0 + SQUARE_TOLERANCE {
+    even_long
+  } else if aspect < 1.0 / (1.0 + SQUARE_TOLERANCE) {
+    !even_long
+  } else {
+    even_flat
+  };
+
+  let first = if even_horizontal { 0 } else { 1 };
+  let top = if angle(first).cos() >= angle(first + 2).cos() { first } else { first + 2 };
+  (top, angle(top).to_degrees())
+}
+
+// Turn a box found by any detector into a DetectedBox.  'corners' go
+// clockwise from any corner, and edges[k] runs from corner k to corner k+1.
+pub fn orient_box(corners: &[Pointf; 4], mut edges: Vec<BoxEdge>, aspect: f64, confidence: f64) -> DetectedBox {
+  let (tl, rotation) = top_left_corner(corners, aspect);
+  let c = |k: usize| corners[(tl + k) % 4];
+  diag!("box rotation={} top left={:?} confidence={}", rotation, c(0), confidence);
+
+  edges.rotate_left(tl);
+  let mut edges = edges.into_iter();
+  let mut e = || edges.next().unwrap();
+  DetectedBox { top_left: c(0), top_right: c(1), bottom_right: c(2), bottom_left: c(3),
+                top: e(), right: e(), bottom: e(), left: e(),
+                rotation, confidence }
+}
+
+pub fn box_finder(i: &Image, options: &FinderOptions) -> Result<DetectedBox, BoxErr> {
+  // Sauvola gives us an image to search where the threshold is maxval/2 everywhere
+  let adjusted;
+  let (i, level) = match options.threshold {
+    ThresholdMode::Scan => (i, None),
+    ThresholdMode::Otsu => (i, Some(threshold::otsu(&i.histogram(0)))),
+    ThresholdMode::Sauvola => {
+      let size = i.get_size();
+      let window = ((size.x.min(size.y) as f64 * SAUVOLA_WINDOW_FRAC) as usize).max(SAUVOLA_MIN_WINDOW);
+      adjusted = threshold::sauvola(i, window, SAUVOLA_K);
+      (&adjusted, Some(i.get_maxval() / 2))
+    },
+  };
+  diag!("box_finder: threshold {:?} level={:?}", options.threshold, level);
+
+  let mut edges = Vec::new();
+  let mut first_err = None;
+  find_edges(i, &MULTI_START_FRACS[..1], level, &mut edges, &mut first_err);
+  if options.search == SearchMode::Multi || edges.len() < 4 {
+    diag!("box_finder: {} edges from the middle of the borders, searching further", edges.len());
+    // If we still fail, it's why the wider search failed that matters
+    let mut multi_err = None;
+    find_edges(i, &MULTI_START_FRACS[1..], level, &mut edges, &mut multi_err);
+    first_err = multi_err.or(first_err);
+  }
+  if edges.len() < 4 {
+    return Err(first_err.unwrap_or(BoxErr { edge: None, failure: Failure::TooFewEdges(edges.len()) }));
+  }
+  edges.truncate(MAX_DISTINCT_EDGES);
+
+  // Pick the set of edges that join up best
+  let mut best : Option<Quad> = None;
+  let mut corner_err = None;
+  let n = edges.len();
+  for a in 0..n {
+    for b in (a + 1)..n {
+      for c in (b + 1)..n {
+        for d in (c + 1)..n {
+          match box_from_edges([&edges[a], &edges[b], &edges[c], &edges[d]]) {
+            Ok(Some(quad)) => {
+              if best.as_ref().is_none_or(|best| quad.error < best.error) {
+                best = Some(quad);
+              }
+            },
+            Ok(None) => (),
+            Err(e) => { corner_err.get_or_insert(e); },
+          }
+        }
+      }
+    }
+  }
+
+  let quad = match best {
+    Some(quad) => quad,
+    None => return Err(corner_err.unwrap_or(BoxErr { edge: None, failure: Failure::TooFewEdges(0) })),
+  };
+  // The fits' own confidence, less when the corners are further from
+  // where the traces ended than the line width they normally stop short by
+  let width = (quad.edges.iter().map(|e| e.width.unwrap_or(1.0)).sum::<f64>() / 4.0).max(1.0);
+  let fit_confidence = quad.edges.iter().map(|e| e.start_line.confidence + e.end_line.confidence).sum::<f64>() / 8.0;
+  let confidence = fit_confidence / (1.0 + (quad.error / (8.0 * width) - 1.0).max(0.0));
+  diag!("box_finder: corner error={}", quad.error);
+  Ok(orient_box(&quad.corners, quad.edges, options.aspect, confidence))
+}
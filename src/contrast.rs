@@ -0,0 +1,201 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Contrast enhancement: linear stretching and CLAHE
+// (Contrast Limited Adaptive Histogram Equalisation)
+
+use std::str::FromStr;
+
+use image::*;
+use point_line::Point;
+
+// Percentage of pixels clipped at each end by default when stretching
+const DEFAULT_STRETCH_CLIP : f64 = 0.5;
+// CLAHE defaults: tiles across each dimension, and how many times the
+// average a histogram bin may hold before it's clipped
+const DEFAULT_CLAHE_TILES : usize = 8;
+const DEFAULT_CLAHE_CLIP : f64 = 2.0;
+// CLAHE histograms use this many bins whatever the maxval
+const CLAHE_BINS : usize = 256;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Enhance {
+  // Percentage to clip at each end
+  Stretch(f64),
+  // Clip limit
+  Clahe(f64),
+}
+
+impl FromStr for Enhance {
+  type Err = String;
+
+  // "stretch", "stretch:PERCENT", "clahe" or "clahe:LIMIT"
+  fn from_str(s: &str) -> Result<Enhance, String> {
+    let (name, arg) = match s.find(':') {
+      Some(colon) => (&s[..colon], Some(&s[colon+1..])),
+      None => (s, None),
+    };
+    let arg = match arg {
+      Some(a) => match a.parse::<f64>() {
+        Ok(v) if v.is_finite() && v >= 0.0 => Some(v),
+        _ => return Err(format!("Bad value '{}' in '{}'", a, s)),
+      },
+      None => None,
+    };
+    match name {
+      "stretch" => match arg {
+        Some(pct) if pct >= 50.0 => Err(format!("Can't clip {}% from each end", pct)),
+        _ => Ok(Enhance::Stretch(arg.unwrap_or(DEFAULT_STRETCH_CLIP))),
+      },
+      "clahe" => Ok(Enhance::Clahe(arg.unwrap_or(DEFAULT_CLAHE_CLIP))),
+      _ => Err(format!("Unknown enhancement '{}' (expected stretch[:PERCENT] or clahe[:LIMIT])", s)),
+    }
+  }
+}
+
+impl Enhance {
+  pub fn apply(self, i: &Image) -> Image {
+    match self {
+      Enhance::Stretch(pct) => stretch(i, pct),
+      Enhance::Clahe(limit) => clahe(i, DEFAULT_CLAHE_TILES, limit),
+    }
+  }
+}
+
+// The value below which 'fraction' of the pixels in 'histogram' lie
+fn percentile(histogram: &[usize], fraction: f64) -> usize {
+  let total = histogram.iter().sum::<usize>() as f64;
+  let mut count = 0;
+  for (v, &n) in histogram.iter().enumerate() {
+    count += n;
+    if count as f64 >= total * fraction {
+      return v;
+    }
+  }
+  histogram.len() - 1
+}
+
+// Linearly stretch the brightness so that 'clip' percent of the pixels
+// end up black and the same white.  Colour channels are all stretched by
+// the same amount, based on the luminance; alpha is left alone.
+pub fn stretch(i: &Image, clip: f64) -> Image {
+  let histogram = i.luminance().histogram(0);
+  let low = percentile(&histogram, clip / 100.0) as f64;
+  let high = percentile(&histogram, 1.0 - clip / 100.0) as f64;
+  if high <= low {
+    return i.clone();
+  }
+
+  let maxval = i.get_maxval() as f64;
+  let scale = maxval / (high - low);
+  let size = i.get_size();
+  let mut result = i.clone();
+  for y in 0..size.y {
+    for x in 0..size.x {
+      let p = Point { x, y };
+      for c in 0..i.get_colour().colour_channels() {
+        let v = (i.get(p, c) as f64 - low) * scale;
+        result.set(p, c, v.round().clamp(0.0, maxval) as u16);
+      }
+    }
+  }
+  result
+}
+
+// The mapping from bin to output level produced by equalising one tile
+// of the luminance, clipping each bin to 'limit' times the average and
+// sharing the excess between all the bins
+fn tile_mapping(lum: &Image, x0: usize, y0: usize, x1: usize, y1: usize, limit: f64) -> Vec<f64> {
+  let maxval = lum.get_maxval() as usize;
+  let mut counts = vec![0.0; CLAHE_BINS];
+  for y in y0..y1 {
+    for x in x0..x1 {
+      counts[lum[Point { x, y }] as usize * CLAHE_BINS / (maxval + 1)] += 1.0;
+    }
+  }
+
+  let total = ((x1 - x0) * (y1 - y0)) as f64;
+  let clip = (limit * total / CLAHE_BINS as f64).max(1.0);
+  let mut excess = 0.0;
+  for n in counts.iter_mut() {
+    if *n > clip {
+      excess += *n - clip;
+      *n = clip;
+    }
+  }
+  let share = excess / CLAHE_BINS as f64;
+
+  // Cumulative, so entry b is the output level for the top of bin b
+  let mut mapping = Vec::with_capacity(CLAHE_BINS);
+  let mut sum = 0.0;
+  for n in counts {
+    sum += n + share;
+    mapping.push(sum / total * maxval as f64);
+  }
+  mapping
+}
+
+// Look up 'v' in a tile's mapping, interpolating within the bin
+fn map_value(mapping: &[f64], v: u16, maxval: u16) -> f64 {
+  let pos = v as f64 * CLAHE_BINS as f64 / (maxval as f64 + 1.0);
+  let bin = (pos as usize).min(CLAHE_BINS - 1);
+  let below = if bin == 0 { 0.0 } else { mapping[bin - 1] };
+  below + (mapping[bin] - below) * (pos - bin as f64)
+}
+
+// Contrast Limited Adaptive Histogram Equalisation: equalise each of
+// tiles x tiles areas separately, limiting how much any one level can be
+// stretched, and blend between the neighbouring tiles' mappings so there
+// are no seams.  Colour images have their luminance equalised and each
+// colour channel scaled to match; alpha is left alone.
+pub fn clahe(i: &Image, tiles: usize, limit: f64) -> Image {
+  let lum = i.luminance();
+  let size = i.get_size();
+  let maxval = i.get_maxval();
+  let tiles_x = tiles.min(size.x).max(1);
+  let tiles_y = tiles.min(size.y).max(1);
+
+  let edge = |t: usize, n: usize, len: usize| t * len / n;
+  let mut mappings = Vec::with_capacity(tiles_x * tiles_y);
+  for ty in 0..tiles_y {
+    for tx in 0..tiles_x {
+      mappings.push(tile_mapping(&lum,
+                                 edge(tx, tiles_x, size.x), edge(ty, tiles_y, size.y),
+                                 edge(tx + 1, tiles_x, size.x), edge(ty + 1, tiles_y, size.y),
+                                 limit));
+    }
+  }
+
+  // Which two tiles (by centre) a coordinate lies between, and how far
+  // from the first to the second
+  let between = |c: usize, n: usize, len: usize| {
+    let pos = ((c as f64 + 0.5) * n as f64 / len as f64 - 0.5).max(0.0);
+    let first = (pos as usize).min(n - 1);
+    (first, (first + 1).min(n - 1), pos - first as f64)
+  };
+
+  let mut result = i.clone();
+  let colours = i.get_colour().colour_channels();
+  for y in 0..size.y {
+    let (ty0, ty1, fy) = between(y, tiles_y, size.y);
+    for x in 0..size.x {
+      let (tx0, tx1, fx) = between(x, tiles_x, size.x);
+      let p = Point { x, y };
+      let v = lum[p];
+      let m = |tx: usize, ty: usize| map_value(&mappings[ty * tiles_x + tx], v, maxval);
+      let top = m(tx0, ty0) * (1.0 - fx) + m(tx1, ty0) * fx;
+      let bottom = m(tx0, ty1) * (1.0 - fx) + m(tx1, ty1) * fx;
+      let new_lum = top * (1.0 - fy) + bottom * fy;
+
+      if colours == 1 {
+        result.set(p, 0, new_lum.round().clamp(0.0, maxval as f64) as u16);
+      } else {
+        for c in 0..colours {
+          let scaled = if v == 0 { new_lum } else { i.get(p, c) as f64 * new_lum / v as f64 };
+          result.set(p, c, scaled.round().clamp(0.0, maxval as f64) as u16);
+        }
+      }
+    }
+  }
+  result
+}
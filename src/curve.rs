@@ -1,11 +1,38 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
 
-// A curve to interpolate between points
-// This is currently a Quadratic Bezier; pretty simple.
+// Curves to interpolate between points
+// Bezierq is a Quadratic Bezier through three points; pretty simple.
+// Bezierc (cubic Bezier) and BSpline are least-squares fitted to a run
+// of points, for edges that bow unevenly or in an S.
+
+use std::str::FromStr;
 
 use point_line::Pointf;
 
+// Segments used by EdgeModel::BSpline when none are given
+const DEFAULT_BSPLINE_SEGMENTS : usize = 3;
+// Straight segments the curve is split into to measure distances from it
+const FIT_STATS_STEPS : usize = 200;
+
+// Anything that runs from a start point (t=0) to an end point (t=1)
+pub trait Curve {
+  fn interp(&self, t: f64) -> Pointf;
+
+  // Approximate arc length, summing 'steps' straight segments
+  fn length(&self, steps: usize) -> f64 {
+    let mut prev = self.interp(0.0);
+    let mut total = 0.0;
+
+    for p in 1..(steps+1) {
+      let cur = self.interp(p as f64 / steps as f64);
+      total += prev.distance(&cur);
+      prev = cur;
+    }
+    total
+  }
+}
+
 pub struct Bezierq {
   pub start : Pointf,
   pub control : Pointf,
@@ -24,13 +51,15 @@ fn find_control(s: f64, m: f64, e: f64, mid_t: f64) -> f64 {
     (2.0 * (1.0 - mid_t) * mid_t)
 }
 
-impl Bezierq {
+impl Curve for Bezierq {
   // Return a point on the curve; t is 0 (start) -> 1 (end)
-  pub fn interp(&self, t: f64) -> Pointf {
+  fn interp(&self, t: f64) -> Pointf {
     Pointf { x: quad_interp(t, self.start.x, self.control.x, self.end.x),
              y: quad_interp(t, self.start.y, self.control.y, self.end.y) }
   }
+}
 
+impl Bezierq {
   // Return a curve that passes through the given points
   // the 'mid' point happens at the specified 't' interpolation point
   pub fn through(s: Pointf, m: Pointf, e: Pointf, mid_t: f64) -> Bezierq {
@@ -40,3 +69,484 @@ impl Bezierq {
                 y: find_control(s.y, m.y, e.y, mid_t) } }
   }
 }
+
+// Parameters for 'points' on a curve from 'start' to 'end', proportional
+// to the distance along the path through them all
+fn chord_lengths(points: &[Pointf], start: Pointf, end: Pointf) -> Vec<f64> {
+  let mut ts = Vec::with_capacity(points.len());
+  let mut prev = start;
+  let mut total = 0.0;
+  for p in points {
+    total += prev.distance(p);
+    ts.push(total);
+    prev = *p;
+  }
+  total += prev.distance(&end);
+  if total > 0.0 {
+    for t in ts.iter_mut() {
+      *t /= total;
+    }
+  }
+  ts
+}
+
+// Solve a x = b by Gaussian elimination with partial pivoting, for the
+// small systems the fits below need; None if 'a' is singular
+fn solve(mut a: Vec<Vec<f64>>, mut b: Vec<Pointf>) -> Option<Vec<Pointf>> {
+  let n = b.len();
+  for col in 0..n {
+    let pivot = (col..n).max_by(|&r1, &r2| a[r1][col].abs().partial_cmp(&a[r2][col].abs()).unwrap())?;
+    if a[pivot][col].abs() < 1e-12 {
+      return None;
+    }
+    a.swap(col, pivot);
+    b.swap(col, pivot);
+    let pivot_row = a[col].clone();
+    for row in (col + 1)..n {
+      let f = a[row][col] / pivot_row[col];
+      for (v, p) in a[row].iter_mut().zip(pivot_row.iter()).skip(col) {
+        *v -= f * p;
+      }
+      b[row].x -= f * b[col].x;
+      b[row].y -= f * b[col].y;
+    }
+  }
+  let mut x = vec![Pointf { x: 0.0, y: 0.0 }; n];
+  for row in (0..n).rev() {
+    let mut v = b[row];
+    for k in (row + 1)..n {
+      v.x -= a[row][k] * x[k].x;
+      v.y -= a[row][k] * x[k].y;
+    }
+    x[row] = Pointf { x: v.x / a[row][row], y: v.y / a[row][row] };
+  }
+  Some(x)
+}
+
+// Least-squares control points for a curve that's a weighted sum of
+// 'count' control points, basis(j, t) being the weight of control point
+// j at t.  The first and last control points are pinned to 'start' and
+// 'end' (as they are the ends of the curve for both Beziers and clamped
+// B-splines) and the rest fitted to 'points'.
+fn fit_controls(points: &[Pointf], start: Pointf, end: Pointf, count: usize,
+                basis: &dyn Fn(usize, f64) -> f64) -> Option<Vec<Pointf>> {
+  let inner = count - 2;
+  if points.len() < inner {
+    return None;
+  }
+  let mut ata = vec![vec![0.0; inner]; inner];
+  let mut atb = vec![Pointf { x: 0.0, y: 0.0 }; inner];
+  for (p, &t) in points.iter().zip(chord_lengths(points, start, end).iter()) {
+    let weights : Vec<f64> = (0..count).map(|j| basis(j, t)).collect();
+    let rx = p.x - weights[0] * start.x - weights[count - 1] * end.x;
+    let ry = p.y - weights[0] * start.y - weights[count - 1] * end.y;
+    for j in 0..inner {
+      for k in 0..inner {
+        ata[j][k] += weights[j + 1] * weights[k + 1];
+      }
+      atb[j].x += weights[j + 1] * rx;
+      atb[j].y += weights[j + 1] * ry;
+    }
+  }
+  let mut controls = vec![start];
+  controls.append(&mut solve(ata, atb)?);
+  controls.push(end);
+  Some(controls)
+}
+
+// Cubic Bezier; two control points let it bow unevenly or in an S
+pub struct Bezierc {
+  pub start : Pointf,
+  pub control1 : Pointf,
+  pub control2 : Pointf,
+  pub end : Pointf,
+}
+
+fn cubic_basis(j: usize, t: f64) -> f64 {
+  let u = 1.0 - t;
+  match j {
+    0 => u * u * u,
+    1 => 3.0 * u * u * t,
+    2 => 3.0 * u * t * t,
+    _ => t * t * t,
+  }
+}
+
+impl Curve for Bezierc {
+  fn interp(&self, t: f64) -> Pointf {
+    let p = [self.start, self.control1, self.control2, self.end];
+    Pointf { x: (0..4).map(|j| cubic_basis(j, t) * p[j].x).sum(),
+             y: (0..4).map(|j| cubic_basis(j, t) * p[j].y).sum() }
+  }
+}
+
+impl Bezierc {
+  // The curve from 'start' to 'end' that best fits 'points' (which
+  // should be in order between them); needs at least two points
+  pub fn fit(points: &[Pointf], start: Pointf, end: Pointf) -> Option<Bezierc> {
+    let c = fit_controls(points, start, end, 4, &cubic_basis)?;
+    Some(Bezierc { start, control1: c[1], control2: c[2], end })
+  }
+}
+
+// Clamped uniform cubic B-spline: 'segments' cubic pieces joined smoothly,
+// passing through the first and last control points.  With one segment
+// it's the same as a Bezierc; more let it follow more complicated shapes.
+pub struct BSpline {
+  pub control : Vec<Pointf>,
+  knots : Vec<f64>,
+}
+
+const BSPLINE_DEGREE : usize = 3;
+
+fn clamped_knots(segments: usize) -> Vec<f64> {
+  let mut knots = vec![0.0; BSPLINE_DEGREE];
+  knots.extend((0..(segments + 1)).map(|k| k as f64 / segments as f64));
+  knots.extend(vec![1.0; BSPLINE_DEGREE]);
+  knots
+}
+
+// Weight of control point j at t (Cox-de Boor); t=1 is taken as the end
+// of the last segment rather than past it
+fn bspline_basis(knots: &[f64], j: usize, t: f64) -> f64 {
+  let last = knots.len() - BSPLINE_DEGREE - 2;
+  let mut n : Vec<f64> = (0..(knots.len() - 1)).map(|k| {
+    if (knots[k] <= t && t < knots[k + 1]) || (t >= 1.0 && k == last) { 1.0 } else { 0.0 }
+  }).collect();
+  for d in 1..(BSPLINE_DEGREE + 1) {
+    for k in 0..(knots.len() - 1 - d) {
+      let left = if knots[k + d] > knots[k] { (t - knots[k]) / (knots[k + d] - knots[k]) * n[k] } else { 0.0 };
+      let right = if knots[k + d + 1] > knots[k + 1] {
+        (knots[k + d + 1] - t) / (knots[k + d + 1] - knots[k + 1]) * n[k + 1]
+      } else {
+        0.0
+      };
+      n[k] = left + right;
+    }
+  }
+  n[j]
+}
+
+impl Curve for BSpline {
+  fn interp(&self, t: f64) -> Pointf {
+    let t = t.clamp(0.0, 1.0);
+    let mut p = Pointf { x: 0.0, y: 0.0 };
+    for (j, c) in self.control.iter().enumerate() {
+      let w = bspline_basis(&self.knots, j, t);
+      p.x += w * c.x;
+      p.y += w * c.y;
+    }
+    p
+  }
+}
+
+impl BSpline {
+  // The spline of 'segments' pieces from 'start' to 'end' that best fits
+  // 'points'; needs at least segments+1 points
+  pub fn fit(points: &[Pointf], start: Pointf, end: Pointf, segments: usize) -> Option<BSpline> {
+    let segments = segments.max(1);
+    let knots = clamped_knots(segments);
+    let control = fit_controls(points, start, end, segments + BSPLINE_DEGREE,
+                               &|j, t| bspline_basis(&knots, j, t))?;
+    Some(BSpline { control, knots })
+  }
+}
+
+// The shape of curve used for the edges of the box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EdgeModel {
+  // Bezierq through the corners and one point half way along
+  Quadratic,
+  // Bezierc fitted to all the points along the edge
+  Cubic,
+  // BSpline of this many segments fitted to all the points
+  BSpline(usize),
+}
+
+impl FromStr for EdgeModel {
+  type Err = String;
+
+  // "quadratic", "cubic", "bspline" or "bspline:SEGMENTS"
+  fn from_str(s: &str) -> Result<EdgeModel, String> {
+    match s {
+      "quadratic" => Ok(EdgeModel::Quadratic),
+      "cubic"     => Ok(EdgeModel::Cubic),
+      "bspline"   => Ok(EdgeModel::BSpline(DEFAULT_BSPLINE_SEGMENTS)),
+      _ if s.starts_with("bspline:") => match s["bspline:".len()..].parse::<usize>() {
+        Ok(n) if n > 0 => Ok(EdgeModel::BSpline(n)),
+        _ => Err(format!("Bad number of segments in '{}'", s)),
+      },
+      _ => Err(format!("Unknown edge model '{}' (expected quadratic, cubic or bspline[:SEGMENTS])", s)),
+    }
+  }
+}
+
+impl EdgeModel {
+  // A curve from 'start' to 'end' fitted to 'points', or for Quadratic
+  // through 'mid'; None if there aren't enough points to fit
+  pub fn fit(self, points: &[Pointf], start: Pointf, mid: Pointf, end: Pointf) -> Option<Box<dyn Curve>> {
+    match self {
+      EdgeModel::Quadratic => Some(Box::new(Bezierq::through(start, mid, end, 0.5))),
+      EdgeModel::Cubic => Bezierc::fit(points, start, end).map(|c| Box::new(c) as Box<dyn Curve>),
+      EdgeModel::BSpline(segments) => BSpline::fit(points, start, end, segments).map(|c| Box::new(c) as Box<dyn Curve>),
+    }
+  }
+}
+
+// How far a set of points is from a curve
+#[derive(Copy, Clone, Debug)]
+pub struct FitStats {
+  pub points: usize,
+  pub rms: f64,
+  pub max: f64,
+}
+
+// Distance from 'p' to the segment from 'a' to 'b'
+fn segment_distance(p: &Pointf, a: &Pointf, b: &Pointf) -> f64 {
+  let (dx, dy) = (b.x - a.x, b.y - a.y);
+  let len2 = dx * dx + dy * dy;
+  let t = if len2 > 0.0 { (((p.x - a.x) * dx + (p.y - a.y) * dy) / len2).clamp(0.0, 1.0) } else { 0.0 };
+  p.distance(&Pointf { x: a.x + t * dx, y: a.y + t * dy })
+}
+
+impl FitStats {
+  // The distance of each of 'points' from the nearest part of 'curve'
+  pub fn new(curve: &dyn Curve, points: &[Pointf]) -> FitStats {
+    let line : Vec<Pointf> = (0..(FIT_STATS_STEPS + 1)).map(|k| curve.interp(k as f64 / FIT_STATS_STEPS as f64)).collect();
+    let mut total = 0.0;
+    let mut max : f64 = 0.0;
+    for p in points {
+      let d = line.windows(2).map(|w| segment_distance(p, &w[0], &w[1])).fold(f64::INFINITY, f64::min);
+      total += d * d;
+      max = max.max(d);
+    }
+    let rms = if points.is_empty() { 0.0 } else { (total / points.len() as f64).sqrt() };
+    FitStats { points: points.len(), rms, max }
+  }
+}
+
+// A Coons patch: the surface bounded by four curves, blending between
+// each opposite pair and correcting for the corners being counted twice.
+// 'top' and 'bottom' run left to right and 'left' and 'right' top to
+// bottom, meeting at the corners.  u runs left to right and v top to
+// bottom, both 0..1.
+pub struct Coons<'a> {
+  top: &'a dyn Curve,
+  bottom: &'a dyn Curve,
+  left: &'a dyn Curve,
+  right: &'a dyn Curve,
+  // Top left, top right, bottom right, bottom left
+  corners: [Pointf; 4],
+}
+
+impl<'a> Coons<'a> {
+  pub fn new(top: &'a dyn Curve, bottom: &'a dyn Curve, left: &'a dyn Curve, right: &'a dyn Curve) -> Coons<'a> {
+    let corners = [top.interp(0.0), top.interp(1.0), bottom.interp(1.0), bottom.interp(0.0)];
+    Coons { top, bottom, left, right, corners }
+  }
+
+  pub fn map(&self, u: f64, v: f64) -> Pointf {
+    let (top, bottom) = self.top_bottom(u);
+    let (left, right) = self.left_right(v);
+    self.blend(u, v, top, bottom, left, right)
+  }
+
+  // The points on the boundary at 'u' across and 'v' down
+  pub fn top_bottom(&self, u: f64) -> (Pointf, Pointf) {
+    (self.top.interp(u), self.bottom.interp(u))
+  }
+
+  pub fn left_right(&self, v: f64) -> (Pointf, Pointf) {
+    (self.left.interp(v), self.right.interp(v))
+  }
+
+  // map() given the points on the boundary from top_bottom(u) and
+  // left_right(v), for callers that can reuse them across many points
+  pub fn blend(&self, u: f64, v: f64, top: Pointf, bottom: Pointf, left: Pointf, right: Pointf) -> Pointf {
+    let c = &self.corners;
+    let points = [top, bottom, left, right, c[0], c[1], c[2], c[3]];
+    let weights = [1.0 - v, v, 1.0 - u, u,
+                   -(1.0 - u) * (1.0 - v), -u * (1.0 - v), -u * v, -(1.0 - u) * v];
+    let mut p = Pointf { x: 0.0, y: 0.0 };
+    for (q, w) in points.iter().zip(weights.iter()) {
+      p.x += w * q.x;
+      p.y += w * q.y;
+    }
+    p
+  }
+
+  // The curve across the patch from top to bottom at 'u'
+  pub fn column(&'a self, u: f64) -> CoonsColumn<'a> {
+    CoonsColumn { patch: self, u }
+  }
+}
+
+pub struct CoonsColumn<'a> {
+  patch: &'a Coons<'a>,
+  u: f64,
+}
+
+impl<'a> Curve for CoonsColumn<'a> {
+  fn interp(&self, t: f64) -> Pointf {
+    self.patch.map(self.u, t)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pt(x: f64, y: f64) -> Pointf {
+    Pointf { x, y }
+  }
+
+  fn assert_near(p: Pointf, x: f64, y: f64, tolerance: f64) {
+    assert!((p.x - x).abs() <= tolerance && (p.y - y).abs() <= tolerance,
+            "{:?} isn't within {} of {},{}", p, tolerance, x, y);
+  }
+
+  // 'count' points strictly between the ends of 'curve'
+  fn sample(curve: &dyn Curve, count: usize) -> Vec<Pointf> {
+    (1..(count + 1)).map(|k| curve.interp(k as f64 / (count + 1) as f64)).collect()
+  }
+
+  // A line from 0,0 to 200,0 bowed 20 up in the middle
+  fn bowed_line() -> Vec<Pointf> {
+    (1..50).map(|k| {
+      let t = k as f64 / 50.0;
+      pt(200.0 * t, 80.0 * t * (1.0 - t))
+    }).collect()
+  }
+
+  // The fits parameterise the points by the distance along them, which
+  // isn't quite the curve's own t, so control points come back close
+  // rather than exact; these curves move along evenly to keep it close
+  #[test]
+  fn cubic_fits_known_cubic() {
+    let known = Bezierc { start: pt(0.0, 0.0), control1: pt(200.0 / 3.0, 12.0),
+                          control2: pt(400.0 / 3.0, -8.0), end: pt(200.0, 10.0) };
+    let points = sample(&known, 50);
+    let fit = Bezierc::fit(&points, known.start, known.end).unwrap();
+    assert_near(fit.control1, known.control1.x, known.control1.y, 1.5);
+    assert_near(fit.control2, known.control2.x, known.control2.y, 1.5);
+
+    let stats = FitStats::new(&fit, &points);
+    assert_eq!(stats.points, 50);
+    assert!(stats.rms < 0.05 && stats.max < 0.1, "{:?}", stats);
+  }
+
+  #[test]
+  fn cubic_fits_bowed_line() {
+    // A parabola is a cubic with both controls 2/3 of the way to the
+    // quadratic's control at 100,40
+    let points = bowed_line();
+    let fit = Bezierc::fit(&points, pt(0.0, 0.0), pt(200.0, 0.0)).unwrap();
+    assert_near(fit.control1, 200.0 / 3.0, 80.0 / 3.0, 4.0);
+    assert_near(fit.control2, 400.0 / 3.0, 80.0 / 3.0, 4.0);
+    assert!((fit.control1.y - fit.control2.y).abs() < 1e-6);
+
+    let stats = FitStats::new(&fit, &points);
+    assert!(stats.rms < 0.25 && stats.max < 0.5, "{:?}", stats);
+  }
+
+  #[test]
+  fn bspline_fits_bowed_line() {
+    let points = bowed_line();
+    let fit = BSpline::fit(&points, pt(0.0, 0.0), pt(200.0, 0.0), 3).unwrap();
+    assert_eq!(fit.control.len(), 6);
+    assert_near(fit.control[0], 0.0, 0.0, 0.0);
+    assert_near(fit.control[5], 200.0, 0.0, 0.0);
+    // Symmetric about the middle
+    for k in 0..3 {
+      assert_near(fit.control[k], 200.0 - fit.control[5 - k].x, fit.control[5 - k].y, 1e-6);
+    }
+    assert_near(fit.interp(0.5), 100.0, 20.0, 0.5);
+
+    let stats = FitStats::new(&fit, &points);
+    assert!(stats.rms < 0.05 && stats.max < 0.1, "{:?}", stats);
+  }
+
+  #[test]
+  fn one_segment_bspline_is_cubic() {
+    let points = bowed_line();
+    let cubic = Bezierc::fit(&points, pt(0.0, 0.0), pt(200.0, 0.0)).unwrap();
+    let spline = BSpline::fit(&points, pt(0.0, 0.0), pt(200.0, 0.0), 1).unwrap();
+    for k in 0..11 {
+      let t = k as f64 / 10.0;
+      let p = cubic.interp(t);
+      assert_near(spline.interp(t), p.x, p.y, 1e-6);
+    }
+  }
+
+  #[test]
+  fn too_few_points_to_fit() {
+    let (s, e) = (pt(0.0, 0.0), pt(10.0, 0.0));
+    assert!(Bezierc::fit(&[], s, e).is_none());
+    assert!(Bezierc::fit(&[pt(5.0, 1.0)], s, e).is_none());
+    // 5 segments have 6 control points to fit between the ends
+    let points : Vec<Pointf> = (1..6).map(|x| pt(x as f64, 1.0)).collect();
+    assert!(BSpline::fit(&points, s, e, 5).is_none());
+    assert!(EdgeModel::BSpline(5).fit(&points, s, pt(5.0, 1.0), e).is_none());
+    // Enough points, but all in one segment so the others are unconstrained
+    let bunched : Vec<Pointf> = (0..10).map(|_| pt(1.0, 1.0)).collect();
+    assert!(BSpline::fit(&bunched, s, e, 5).is_none());
+  }
+
+  #[test]
+  fn fit_stats() {
+    let line = Bezierq::through(pt(0.0, 0.0), pt(50.0, 0.0), pt(100.0, 0.0), 0.5);
+    let stats = FitStats::new(&line, &[pt(10.0, 3.0), pt(50.0, -4.0), pt(90.0, 0.0)]);
+    assert_eq!(stats.points, 3);
+    assert!((stats.max - 4.0).abs() < 1e-9);
+    assert!((stats.rms - (25.0f64 / 3.0).sqrt()).abs() < 1e-9);
+    assert_eq!(FitStats::new(&line, &[]).rms, 0.0);
+  }
+
+  #[test]
+  fn edge_model_names() {
+    assert_eq!("cubic".parse::<EdgeModel>(), Ok(EdgeModel::Cubic));
+    assert_eq!("bspline".parse::<EdgeModel>(), Ok(EdgeModel::BSpline(DEFAULT_BSPLINE_SEGMENTS)));
+    assert_eq!("bspline:7".parse::<EdgeModel>(), Ok(EdgeModel::BSpline(7)));
+    assert!("bspline:0".parse::<EdgeModel>().is_err());
+    assert!("spline".parse::<EdgeModel>().is_err());
+  }
+
+  // A straight Bezierq from 'a' to 'b'
+  fn straight(a: Pointf, b: Pointf) -> Bezierq {
+    Bezierq::through(a, pt((a.x + b.x) / 2.0, (a.y + b.y) / 2.0), b, 0.5)
+  }
+
+  #[test]
+  fn coons_follows_its_edges() {
+    let top = Bezierq::through(pt(0.0, 0.0), pt(50.0, -5.0), pt(100.0, 2.0), 0.5);
+    let bottom = Bezierq::through(pt(-3.0, 60.0), pt(50.0, 70.0), pt(104.0, 58.0), 0.5);
+    let left = Bezierq::through(pt(0.0, 0.0), pt(-6.0, 30.0), pt(-3.0, 60.0), 0.5);
+    let right = Bezierq::through(pt(100.0, 2.0), pt(108.0, 30.0), pt(104.0, 58.0), 0.5);
+    let patch = Coons::new(&top, &bottom, &left, &right);
+    for k in 0..11 {
+      let t = k as f64 / 10.0;
+      for &(p, q) in &[(patch.map(t, 0.0), top.interp(t)), (patch.map(t, 1.0), bottom.interp(t)),
+                       (patch.map(0.0, t), left.interp(t)), (patch.map(1.0, t), right.interp(t)),
+                       (patch.column(0.3).interp(t), patch.map(0.3, t))] {
+        assert_near(p, q.x, q.y, 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn coons_of_straight_sides_is_bilinear() {
+    let c = [pt(10.0, 5.0), pt(110.0, 15.0), pt(100.0, 70.0), pt(5.0, 60.0)];
+    let (top, bottom) = (straight(c[0], c[1]), straight(c[3], c[2]));
+    let (left, right) = (straight(c[0], c[3]), straight(c[1], c[2]));
+    let patch = Coons::new(&top, &bottom, &left, &right);
+    for j in 0..11 {
+      for k in 0..11 {
+        let (u, v) = (j as f64 / 10.0, k as f64 / 10.0);
+        let w = [(1.0 - u) * (1.0 - v), u * (1.0 - v), u * v, (1.0 - u) * v];
+        let x = (0..4).map(|n| w[n] * c[n].x).sum();
+        let y = (0..4).map(|n| w[n] * c[n].y).sum();
+        assert_near(patch.map(u, v), x, y, 1e-9);
+      }
+    }
+  }
+}
@@ -0,0 +1,202 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Edge detection: Sobel gradients and Canny edges
+// Only the first channel is used, so pass a luminance image for colour
+
+use std::f64;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use filter;
+use image::*;
+use point_line::Point;
+
+// Canny's hysteresis thresholds as fractions of the strongest gradient
+const CANNY_LOW : f64 = 0.1;
+const CANNY_HIGH : f64 = 0.2;
+// Radius of the blur applied before Canny, and to its thin edges before
+// they're used for detection
+const CANNY_BLUR_RADIUS : usize = 2;
+
+// The gradient at each pixel; direction is in radians, pointing from dark
+// towards light
+pub struct Gradient {
+  width: usize,
+  height: usize,
+  magnitude: Vec<f64>,
+  direction: Vec<f64>,
+}
+
+impl Gradient {
+  pub fn magnitude(&self, p: Point) -> f64 {
+    self.magnitude[p.y * self.width + p.x]
+  }
+
+  pub fn direction(&self, p: Point) -> f64 {
+    self.direction[p.y * self.width + p.x]
+  }
+
+  pub fn max_magnitude(&self) -> f64 {
+    self.magnitude.iter().cloned().fold(0.0, f64::max)
+  }
+
+  // The magnitude as a grey image, scaled so the strongest edge is maxval
+  pub fn magnitude_image(&self, maxval: u16) -> Image {
+    let max = self.max_magnitude();
+    let scale = if max > 0.0 { maxval as f64 / max } else { 0.0 };
+    let mut result = Image::new(self.width, self.height, ColourType::Grey, maxval);
+    for y in 0..self.height {
+      for x in 0..self.width {
+        let p = Point { x, y };
+        result[p] = (self.magnitude(p) * scale).round() as u16;
+      }
+    }
+    result
+  }
+}
+
+// Sobel operator, treating off the edge as the nearest edge pixel
+pub fn sobel(i: &Image) -> Gradient {
+  let size = i.get_size();
+  let at = |x: usize, y: usize, dx: isize, dy: isize| {
+    let px = (x as isize + dx).max(0).min(size.x as isize - 1) as usize;
+    let py = (y as isize + dy).max(0).min(size.y as isize - 1) as usize;
+    i.get(Point { x: px, y: py }, 0) as f64
+  };
+
+  let mut magnitude = Vec::with_capacity(size.x * size.y);
+  let mut direction = Vec::with_capacity(size.x * size.y);
+  for y in 0..size.y {
+    for x in 0..size.x {
+      let gx = (at(x, y, 1, -1) + 2.0 * at(x, y, 1, 0) + at(x, y, 1, 1)) -
+               (at(x, y, -1, -1) + 2.0 * at(x, y, -1, 0) + at(x, y, -1, 1));
+      let gy = (at(x, y, -1, 1) + 2.0 * at(x, y, 0, 1) + at(x, y, 1, 1)) -
+               (at(x, y, -1, -1) + 2.0 * at(x, y, 0, -1) + at(x, y, 1, -1));
+      magnitude.push((gx * gx + gy * gy).sqrt());
+      direction.push(gy.atan2(gx));
+    }
+  }
+  Gradient { width: size.x, height: size.y, magnitude, direction }
+}
+
+// Canny edge detection: blur, Sobel, thin the edges to one pixel by only
+// keeping the peaks across them, then keep weak edges only where they
+// join on to strong ones.  Returns edges as maxval on a 0 background.
+// 'low' and 'high' are fractions of the strongest gradient.
+pub fn canny(i: &Image, blur_radius: usize, low: f64, high: f64) -> Image {
+  let blurred = filter::gaussian_blur(i, blur_radius);
+  let gradient = sobel(&blurred);
+  let size = i.get_size();
+  let max = gradient.max_magnitude();
+  let maxval = i.get_maxval();
+  // A flat image has no edges; without this both thresholds would be 0
+  // and every pixel would count as one
+  if max <= 0.0 {
+    return Image::new(size.x, size.y, ColourType::Grey, maxval);
+  }
+  let low = low * max;
+  let high = high * max;
+
+  // Non-maximum suppression; compare with the neighbours either side
+  // along the (quantised) gradient direction
+  let mut thin = vec![0.0; size.x * size.y];
+  for y in 1..size.y.saturating_sub(1) {
+    for x in 1..size.x.saturating_sub(1) {
+      let p = Point { x, y };
+      let m = gradient.magnitude(p);
+      if m < low { continue; }
+      let angle = (gradient.direction(p) + PI) % PI;
+      let (dx, dy) : (isize, isize) = if !(PI / 8.0..7.0 * PI / 8.0).contains(&angle) {
+        (1, 0)
+      } else if angle < 3.0 * PI / 8.0 {
+        (1, 1)
+      } else if angle < 5.0 * PI / 8.0 {
+        (0, 1)
+      } else {
+        (-1, 1)
+      };
+      let before = Point { x: (x as isize - dx) as usize, y: (y as isize - dy) as usize };
+      let after = Point { x: (x as isize + dx) as usize, y: (y as isize + dy) as usize };
+      if m >= gradient.magnitude(before) && m >= gradient.magnitude(after) {
+        thin[y * size.x + x] = m;
+      }
+    }
+  }
+
+  // Hysteresis: flood out from the strong edges through the weak ones;
+  // pixels suppressed above are never edges however low the thresholds
+  let mut result = Image::new(size.x, size.y, ColourType::Grey, maxval);
+  let mut stack : Vec<Point> = Vec::new();
+  for y in 0..size.y {
+    for x in 0..size.x {
+      let m = thin[y * size.x + x];
+      if m > 0.0 && m >= high {
+        stack.push(Point { x, y });
+      }
+    }
+  }
+  while let Some(p) = stack.pop() {
+    if result[p] != 0 { continue; }
+    result[p] = maxval;
+    for ny in p.y.saturating_sub(1)..(p.y + 2).min(size.y) {
+      for nx in p.x.saturating_sub(1)..(p.x + 2).min(size.x) {
+        let n = Point { x: nx, y: ny };
+        let m = thin[ny * size.x + nx];
+        if result[n] == 0 && m > 0.0 && m >= low {
+          stack.push(n);
+        }
+      }
+    }
+  }
+  result
+}
+
+// Which edge map to make
+#[derive(Copy, Clone, Debug)]
+pub enum EdgeMap {
+  Sobel,
+  Canny,
+}
+
+impl FromStr for EdgeMap {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<EdgeMap, String> {
+    match s {
+      "sobel" => Ok(EdgeMap::Sobel),
+      "canny" => Ok(EdgeMap::Canny),
+      _ => Err(format!("Unknown edge map '{}' (expected sobel or canny)", s)),
+    }
+  }
+}
+
+impl EdgeMap {
+  // The edge map of 'i' with edges bright on a dark background
+  pub fn apply(self, i: &Image) -> Image {
+    match self {
+      EdgeMap::Sobel => sobel(i).magnitude_image(i.get_maxval()),
+      EdgeMap::Canny => canny(i, CANNY_BLUR_RADIUS, CANNY_LOW, CANNY_HIGH),
+    }
+  }
+
+  // An image for box_finder to search: the edges as dark lines on white.
+  // Both sides of the marker line show up as edges, so box_finder ends up
+  // following the outside of the line rather than its middle.  Canny's
+  // single pixel edges are blurred a little so they can still be centred on.
+  pub fn for_detection(self, i: &Image) -> Image {
+    let mut map = self.apply(i);
+    if let EdgeMap::Canny = self {
+      map = filter::gaussian_blur(&map, CANNY_BLUR_RADIUS);
+    }
+    let size = map.get_size();
+    let maxval = map.get_maxval();
+    for y in 0..size.y {
+      for x in 0..size.x {
+        let p = Point { x, y };
+        map[p] = maxval - map[p];
+      }
+    }
+    map
+  }
+}
@@ -0,0 +1,138 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Noise reduction filters
+// Off the edge of the image takes the value of the nearest edge pixel
+
+use std::str::FromStr;
+
+use image::*;
+use point_line::Point;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Denoise {
+  Gaussian(usize),
+  Median(usize),
+}
+
+impl FromStr for Denoise {
+  type Err = String;
+
+  // "gaussian:RADIUS" or "median:RADIUS"
+  fn from_str(s: &str) -> Result<Denoise, String> {
+    let (name, radius) = match s.find(':') {
+      Some(colon) => match s[colon+1..].parse::<usize>() {
+        Ok(r) if r > 0 => (&s[..colon], r),
+        _ => return Err(format!("Bad radius in '{}'", s)),
+      },
+      None => return Err(format!("'{}' needs a radius, e.g. {}:2", s, s)),
+    };
+    match name {
+      "gaussian" => Ok(Denoise::Gaussian(radius)),
+      "median"   => Ok(Denoise::Median(radius)),
+      _ => Err(format!("Unknown filter '{}' (expected gaussian:RADIUS or median:RADIUS)", s)),
+    }
+  }
+}
+
+impl Denoise {
+  pub fn apply(self, i: &Image) -> Image {
+    match self {
+      Denoise::Gaussian(radius) => gaussian_blur(i, radius),
+      Denoise::Median(radius) => median(i, radius),
+    }
+  }
+}
+
+fn clamp_offset(c: usize, offset: isize, limit: usize) -> usize {
+  (c as isize + offset).max(0).min(limit as isize - 1) as usize
+}
+
+// Gaussian blur over +/-radius pixels, with sigma radius/3 so the kernel
+// covers nearly all of the curve.  Being separable we do it as a
+// horizontal pass then a vertical one.  A radius of 0 leaves the image
+// alone.
+pub fn gaussian_blur(i: &Image, radius: usize) -> Image {
+  if radius == 0 {
+    return i.clone();
+  }
+  let sigma = radius as f64 / 3.0;
+  let r = radius as isize;
+  let mut kernel : Vec<f64> = (-r..(r + 1)).map(|k| (-(k * k) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+  let total = kernel.iter().sum::<f64>();
+  for w in kernel.iter_mut() {
+    *w /= total;
+  }
+
+  let size = i.get_size();
+  let channels = i.get_colour().channels();
+  let pass = |src: &Image, horizontal: bool| {
+    let mut dst = src.clone();
+    for y in 0..size.y {
+      for x in 0..size.x {
+        for c in 0..channels {
+          let mut v = 0.0;
+          for (k, w) in kernel.iter().enumerate() {
+            let offset = k as isize - r;
+            let p = if horizontal {
+              Point { x: clamp_offset(x, offset, size.x), y }
+            } else {
+              Point { x, y: clamp_offset(y, offset, size.y) }
+            };
+            v += w * src.get(p, c) as f64;
+          }
+          dst.set(Point { x, y }, c, v.round() as u16);
+        }
+      }
+    }
+    dst
+  };
+  pass(&pass(i, true), false)
+}
+
+// Median over a (2*radius+1) square; unlike a blur it removes speckle
+// without softening the edges of the line
+pub fn median(i: &Image, radius: usize) -> Image {
+  let size = i.get_size();
+  let channels = i.get_colour().channels();
+  let r = radius as isize;
+  let mut window = Vec::with_capacity((2 * radius + 1) * (2 * radius + 1));
+  let mut result = i.clone();
+  for y in 0..size.y {
+    for x in 0..size.x {
+      for c in 0..channels {
+        window.clear();
+        for dy in -r..(r + 1) {
+          for dx in -r..(r + 1) {
+            let p = Point { x: clamp_offset(x, dx, size.x), y: clamp_offset(y, dy, size.y) };
+            window.push(i.get(p, c));
+          }
+        }
+        let mid = window.len() / 2;
+        result.set(Point { x, y }, c, *window.select_nth_unstable(mid).1);
+      }
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn gaussian_blur_radius_zero() {
+    let mut i = Image::new(5, 3, ColourType::Grey, 255);
+    i.set(Point { x: 2, y: 1 }, 0, 200);
+    let blurred = gaussian_blur(&i, 0);
+    for y in 0..3 {
+      for x in 0..5 {
+        assert_eq!(blurred.get(Point { x, y }, 0), i.get(Point { x, y }, 0));
+      }
+    }
+    // While any real radius spreads the spot out
+    let blurred = gaussian_blur(&i, 1);
+    assert!(blurred.get(Point { x: 2, y: 1 }, 0) < 200);
+    assert!(blurred.get(Point { x: 1, y: 1 }, 0) > 0);
+  }
+}
@@ -0,0 +1,430 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Finding the box with a Hough transform: the strongest straight lines in
+// an edge map are found, and the four of them that make the quadrilateral
+// with the most of its perimeter really there in the edge map are taken
+// to be the box.  Working on edges it finds the outside of the line, like
+// box_finder does on an edge map.
+
+use std::f64;
+use std::f64::consts::PI;
+
+use box_finder::{orient_box, BoxEdge, BoxErr, DetectedBox, Failure, FinderOptions};
+use image::*;
+use line_fit::*;
+use point_line::*;
+
+// Number of angles the lines are binned into over 180 degrees
+const HOUGH_ANGLES : usize = 180;
+// The most lines considered, and the fewest votes one can have as a
+// fraction of the strongest line's
+const HOUGH_MAX_LINES : usize = 20;
+const HOUGH_MIN_VOTES_FRAC : f64 = 0.2;
+// Around each line found, votes within this many angle bins and pixels
+// are cleared so the same line isn't found twice; a bowed side votes for
+// lines across the whole of its bow, so at least suppress_reach() pixels
+// are cleared
+const HOUGH_SUPPRESS_ANGLE : usize = 4;
+const HOUGH_SUPPRESS_RHO : usize = 4;
+// Opposite sides must be within this many degrees of parallel, and the
+// two pairs of sides at least this far from it
+const HOUGH_PARALLEL_DEG : f64 = 30.0;
+const HOUGH_CROSSING_DEG : f64 = 45.0;
+// Sides shorter than this fraction of the image's smaller dimension are
+// ignored, and corners may be at most this far off the image
+const HOUGH_MIN_SIDE_FRAC : f64 = 0.1;
+const HOUGH_CORNER_MARGIN_FRAC : f64 = 0.02;
+// The fraction of each side that must lie on edge pixels; the ends are
+// skipped since corners are often rounded
+const HOUGH_MIN_SUPPORT : f64 = 0.6;
+const HOUGH_SUPPORT_END_FRAC : f64 = 0.1;
+// Edge pixels within this distance (pixels) of a line are used to refine it
+const HOUGH_FIT_DISTANCE : f64 = 1.5;
+// How far (as a fraction of its length) either side of a straight side
+// we look for the edge along it, so that bowed edges get points that are
+// on them
+const HOUGH_EDGE_REACH_FRAC : f64 = 0.05;
+// The points within this fraction of each end of a side are used to fit
+// the line the side meets its corner along
+const HOUGH_END_FIT_FRAC : f64 = 0.2;
+const HOUGH_END_FIT_ITERATIONS : usize = 200;
+
+// A line x*cos(theta) + y*sin(theta) = rho from the accumulator
+#[derive(Copy, Clone, Debug)]
+struct HoughLine {
+  theta: f64,
+  rho: f64,
+  votes: usize,
+}
+
+impl HoughLine {
+  fn to_fit(self) -> LineFit {
+    let (sin, cos) = self.theta.sin_cos();
+    LineFit { point: Pointf { x: self.rho * cos, y: self.rho * sin },
+              dir: Pointf { x: -sin, y: cos },
+              residual: 0.0, inliers: self.votes, confidence: 1.0 }
+  }
+}
+
+fn is_edge(edges: &Image, p: Pointf) -> bool {
+  let size = edges.get_size();
+  let (x, y) = (p.x.round(), p.y.round());
+  if x < 0.0 || y < 0.0 || x >= size.x as f64 || y >= size.y as f64 {
+    return false;
+  }
+  edges.get(Point { x: x as usize, y: y as usize }, 0) > edges.get_maxval() / 2
+}
+
+// How far either side of a line hough_lines clears the votes for it; the
+// edge of a line it clears can be this far from the line found
+fn suppress_reach(size: Point) -> f64 {
+  size.x.min(size.y) as f64 * HOUGH_EDGE_REACH_FRAC
+}
+
+// Vote for every line through every edge pixel, then pick out the
+// strongest lines one at a time
+fn hough_lines(edges: &Image) -> Vec<HoughLine> {
+  let size = edges.get_size();
+  let max_rho = ((size.x * size.x + size.y * size.y) as f64).sqrt().ceil() as usize;
+  let rhos = 2 * max_rho + 1;
+  let suppress_rho = HOUGH_SUPPRESS_RHO.max(suppress_reach(size) as usize);
+  let trig : Vec<(f64, f64)> = (0..HOUGH_ANGLES).map(|t| (t as f64 * PI / HOUGH_ANGLES as f64).sin_cos()).collect();
+
+  let mut acc = vec![0usize; HOUGH_ANGLES * rhos];
+  for y in 0..size.y {
+    for x in 0..size.x {
+      if !is_edge(edges, Point { x, y }.into()) { continue; }
+      for (t, &(sin, cos)) in trig.iter().enumerate() {
+        let r = (x as f64 * cos + y as f64 * sin).round() as isize + max_rho as isize;
+        acc[t * rhos + r as usize] += 1;
+      }
+    }
+  }
+
+  let mut lines : Vec<HoughLine> = Vec::new();
+  while lines.len() < HOUGH_MAX_LINES {
+    let (best, &votes) = match acc.iter().enumerate().max_by_key(|&(_, v)| *v) {
+      Some(b) => b,
+      None => break,
+    };
+    if votes == 0 || lines.first().is_some_and(|l| (votes as f64) < l.votes as f64 * HOUGH_MIN_VOTES_FRAC) {
+      break;
+    }
+    let (t, r) = (best / rhos, best % rhos);
+    lines.push(HoughLine { theta: t as f64 * PI / HOUGH_ANGLES as f64,
+                           rho: r as f64 - max_rho as f64, votes });
+
+    // Angles wrap round at 180 degrees with rho changing sign
+    let s = HOUGH_SUPPRESS_ANGLE as isize;
+    for dt in -s..(s + 1) {
+      let mut tt = t as isize + dt;
+      let mut rr = r;
+      if tt < 0 || tt >= HOUGH_ANGLES as isize {
+        tt = (tt + HOUGH_ANGLES as isize) % HOUGH_ANGLES as isize;
+        rr = rhos - 1 - r;
+      }
+      for ri in rr.saturating_sub(suppress_rho)..(rr + suppress_rho + 1).min(rhos) {
+        acc[tt as usize * rhos + ri] = 0;
+      }
+    }
+  }
+  lines
+}
+
+// Angle in degrees between two lines, 0..90
+fn angle_between(a: &HoughLine, b: &HoughLine) -> f64 {
+  let d = (a.theta - b.theta).abs().to_degrees() % 180.0;
+  d.min(180.0 - d)
+}
+
+// The fraction of the side from 'a' to 'b' (less its ends) that has an
+// edge across it, within the reach side_points uses so a bowed side
+// still counts as being there
+fn support(edges: &Image, a: Pointf, b: Pointf) -> f64 {
+  let length = a.distance(&b);
+  let dir = match a.unit_to(&b) {
+    Some(d) => d,
+    None => return 0.0,
+  };
+  let reach = (length * HOUGH_EDGE_REACH_FRAC).max(1.0);
+  let start = length * HOUGH_SUPPORT_END_FRAC;
+  let steps = (length * (1.0 - 2.0 * HOUGH_SUPPORT_END_FRAC)) as usize;
+  if steps == 0 {
+    return 0.0;
+  }
+  let hits = (0..steps).filter(|&s| {
+    let p = a.along(dir, start + s as f64);
+    edge_across(edges, p, dir, p, reach).is_some()
+  }).count();
+  hits as f64 / steps as f64
+}
+
+// Fit a line to the edge pixels close to the side from 'a' to 'b'
+fn refine_side(edges: &Image, line: &LineFit, a: Pointf, b: Pointf) -> Option<LineFit> {
+  let size = edges.get_size();
+  let reach = HOUGH_FIT_DISTANCE.ceil() as usize;
+  let (x0, x1) = (a.x.min(b.x).max(0.0) as usize, (a.x.max(b.x).ceil() as usize).min(size.x - 1));
+  let (y0, y1) = (a.y.min(b.y).max(0.0) as usize, (a.y.max(b.y).ceil() as usize).min(size.y - 1));
+  let length = a.distance(&b);
+  let dir = a.unit_to(&b)?;
+
+  let mut points = Vec::new();
+  for y in y0.saturating_sub(reach)..(y1 + reach + 1).min(size.y) {
+    for x in x0.saturating_sub(reach)..(x1 + reach + 1).min(size.x) {
+      let p = Pointf { x: x as f64, y: y as f64 };
+      let along = (p.x - a.x) * dir.x + (p.y - a.y) * dir.y;
+      if along < length * HOUGH_SUPPORT_END_FRAC || along > length * (1.0 - HOUGH_SUPPORT_END_FRAC) { continue; }
+      if line.distance(p) <= HOUGH_FIT_DISTANCE && is_edge(edges, p) {
+        points.push(p);
+      }
+    }
+  }
+  total_least_squares(&points)
+}
+
+// Look across the side at 'p' for the edge, coming in from the outside
+// (away from 'centre') so we find the outer edge of the line as the rest
+// of the side does; 'dir' is along the side
+fn edge_across(edges: &Image, p: Pointf, dir: Pointf, centre: Pointf, reach: f64) -> Option<Pointf> {
+  let mut inwards = Pointf { x: -dir.y, y: dir.x };
+  if inwards.x * (centre.x - p.x) + inwards.y * (centre.y - p.y) < 0.0 {
+    inwards = Pointf { x: -inwards.x, y: -inwards.y };
+  }
+  let mut offset = -reach;
+  while offset <= reach {
+    let q = p.along(inwards, offset);
+    if is_edge(edges, q) {
+      return Some(q);
+    }
+    offset += 0.5;
+  }
+  None
+}
+
+// The midpoint of the side from 'a' to 'b' and the points along it (less
+// its ends), moved on to the edge so they follow it if it's bowed.  We
+// look at least 'min_reach' either side for it
+fn side_points(edges: &Image, a: Pointf, b: Pointf, centre: Pointf, min_reach: f64) -> (Pointf, Vec<Pointf>) {
+  let mid = Pointf { x: (a.x + b.x) / 2.0, y: (a.y + b.y) / 2.0 };
+  let dir = match a.unit_to(&b) {
+    Some(d) => d,
+    None => return (mid, Vec::new()),
+  };
+  let length = a.distance(&b);
+  let reach = (length * HOUGH_EDGE_REACH_FRAC).max(min_reach);
+  let start = length * HOUGH_SUPPORT_END_FRAC;
+  let steps = (length * (1.0 - 2.0 * HOUGH_SUPPORT_END_FRAC)) as usize;
+  let points = (0..steps).filter_map(|s| edge_across(edges, a.along(dir, start + s as f64), dir, centre, reach))
+                         .collect();
+  (edge_across(edges, mid, dir, centre, reach).unwrap_or(mid), points)
+}
+
+// Lines fitted to 'points' (from side_points) near each end of the side
+// from 'a' to 'b'; where the side is bowed these are the directions it
+// meets its corners in
+fn end_lines(points: &[Pointf], a: Pointf, b: Pointf) -> Option<(LineFit, LineFit)> {
+  let length = a.distance(&b);
+  let dir = a.unit_to(&b)?;
+  let along = |p: &Pointf| ((p.x - a.x) * dir.x + (p.y - a.y) * dir.y) / length;
+  let near_a : Vec<Pointf> = points.iter().filter(|p| along(p) < HOUGH_END_FIT_FRAC).cloned().collect();
+  let near_b : Vec<Pointf> = points.iter().filter(|p| along(p) > 1.0 - HOUGH_END_FIT_FRAC).cloned().collect();
+  Some((ransac(&near_a, HOUGH_FIT_DISTANCE, HOUGH_END_FIT_ITERATIONS)?,
+        ransac(&near_b, HOUGH_FIT_DISTANCE, HOUGH_END_FIT_ITERATIONS)?))
+}
+
+// Corners of the box made by two pairs of opposite sides, clockwise, or
+// None if they don't make a sensible box on the image
+fn quad_corners(pair1: (&LineFit, &LineFit), pair2: (&LineFit, &LineFit), size: Point) -> Option<[Pointf; 4]> {
+  let mut corners = [pair1.0.intersection(pair2.0)?, pair1.0.intersection(pair2.1)?,
+                     pair1.1.intersection(pair2.1)?, pair1.1.intersection(pair2.0)?];
+  let cx = corners.iter().map(|c| c.x).sum::<f64>() / 4.0;
+  let cy = corners.iter().map(|c| c.y).sum::<f64>() / 4.0;
+  corners.sort_by(|a, b| (a.y - cy).atan2(a.x - cx).partial_cmp(&(b.y - cy).atan2(b.x - cx)).unwrap());
+
+  let smaller = size.x.min(size.y) as f64;
+  let margin = smaller * HOUGH_CORNER_MARGIN_FRAC;
+  for k in 0..4 {
+    let (a, b, c) = (corners[k], corners[(k + 1) % 4], corners[(k + 2) % 4]);
+    if a.x < -margin || a.y < -margin || a.x > size.x as f64 + margin || a.y > size.y as f64 + margin ||
+       a.distance(&b) < smaller * HOUGH_MIN_SIDE_FRAC ||
+       (b.x - a.x) * (c.y - b.y) - (b.y - a.y) * (c.x - b.x) <= 0.0 {
+      return None;
+    }
+  }
+  Some(corners)
+}
+
+// Find the box in 'edges', an edge map with the edges bright (as from
+// edges::EdgeMap::apply).  Only the aspect of 'options' is used.
+pub fn hough_finder(edges: &Image, options: &FinderOptions) -> Result<DetectedBox, BoxErr> {
+  let size = edges.get_size();
+  let lines = hough_lines(edges);
+  diag!("hough_finder: {} lines", lines.len());
+  if lines.len() < 4 {
+    return Err(BoxErr { edge: None, failure: Failure::TooFewEdges(lines.len()) });
+  }
+  let unsupported = BoxErr { edge: None, failure: Failure::NoSupportedBox(lines.len()) };
+  let fits : Vec<LineFit> = lines.iter().map(|l| l.to_fit()).collect();
+
+  // Every pair of nearly parallel lines could be opposite sides
+  let mut pairs = Vec::new();
+  for a in 0..lines.len() {
+    for b in (a + 1)..lines.len() {
+      if angle_between(&lines[a], &lines[b]) <= HOUGH_PARALLEL_DEG {
+        pairs.push((a, b));
+      }
+    }
+  }
+
+  // The box with the longest perimeter on edges whose sides are all
+  // there.  Sides well off a bowed edge can still have much of it within
+  // reach, so a box isn't picked just for being bigger
+  let mut best : Option<([Pointf; 4], f64)> = None;
+  for (i, &(a, b)) in pairs.iter().enumerate() {
+    for &(c, d) in &pairs[i + 1..] {
+      if [a, b].iter().any(|&l| l == c || l == d) ||
+         angle_between(&lines[a], &lines[c]) < HOUGH_CROSSING_DEG {
+        continue;
+      }
+      let corners = match quad_corners((&fits[a], &fits[b]), (&fits[c], &fits[d]), size) {
+        Some(corners) => corners,
+        None => continue,
+      };
+      let lengths : Vec<f64> = (0..4).map(|k| corners[k].distance(&corners[(k + 1) % 4])).collect();
+      if best.as_ref().is_some_and(|&(_, best_on)| lengths.iter().sum::<f64>() <= best_on) {
+        continue;
+      }
+      let supports : Vec<f64> = (0..4).map(|k| support(edges, corners[k], corners[(k + 1) % 4])).collect();
+      if supports.iter().any(|&s| s < HOUGH_MIN_SUPPORT) {
+        continue;
+      }
+      let on_edges = supports.iter().zip(&lengths).map(|(s, l)| s * l).sum::<f64>();
+      if best.as_ref().is_none_or(|&(_, best_on)| on_edges > best_on) {
+        best = Some((corners, on_edges));
+      }
+    }
+  }
+  let (corners, _) = best.ok_or(unsupported)?;
+
+  // The accumulator only has whole pixel and degree accuracy, so fit the
+  // sides again to the edge pixels along them
+  let mut rough = Vec::with_capacity(4);
+  for k in 0..4 {
+    let (a, b) = (corners[k], corners[(k + 1) % 4]);
+    let dir = a.unit_to(&b).ok_or(unsupported)?;
+    rough.push(LineFit { point: a, dir, residual: 0.0, inliers: 0, confidence: 0.0 });
+  }
+  let side_fits : Vec<Option<LineFit>> = (0..4).map(|k| refine_side(edges, &rough[k], corners[k], corners[(k + 1) % 4])).collect();
+  let mut refined = corners;
+  if side_fits.iter().all(|f| f.is_some()) {
+    for k in 0..4 {
+      let prev = side_fits[(k + 3) % 4].as_ref().unwrap();
+      let next = side_fits[k].as_ref().unwrap();
+      if let Some(p) = prev.intersection(next) {
+        refined[k] = p;
+      }
+    }
+  }
+
+  let centre = Pointf { x: refined.iter().map(|c| c.x).sum::<f64>() / 4.0,
+                        y: refined.iter().map(|c| c.y).sum::<f64>() / 4.0 };
+  let sides = |corners: &[Pointf; 4], min_reach: f64| -> Vec<(Pointf, Vec<Pointf>)> {
+    (0..4).map(|k| side_points(edges, corners[k], corners[(k + 1) % 4], centre, min_reach)).collect()
+  };
+
+  // A bowed side meets its corners at an angle to any one straight line
+  // along it, and that line can be off the edge by as much as the bow; so
+  // take the corners from lines fitted to the edge near the ends of each
+  // side instead.  The line for the edge itself may have been cleared by
+  // a stronger one close inside it, so look as far as hough_lines cleared
+  let mut lines : Vec<(LineFit, LineFit)> = (0..4).map(|k| {
+    let line = side_fits[k].unwrap_or(rough[k]);
+    (line, line)
+  }).collect();
+  let points = sides(&refined, suppress_reach(size));
+  let ends : Option<Vec<(LineFit, LineFit)>> = (0..4).map(|k| end_lines(&points[k].1, refined[k], refined[(k + 1) % 4])).collect();
+  if let Some(ends) = ends {
+    let ended : Option<Vec<Pointf>> = (0..4).map(|k| ends[(k + 3) % 4].1.intersection(&ends[k].0)).collect();
+    if let Some(ended) = ended {
+      refined.copy_from_slice(&ended);
+      lines = ends;
+    }
+  }
+
+  let mut box_edges = Vec::with_capacity(4);
+  let mut total_support = 0.0;
+  for (k, (mid, points)) in sides(&refined, 0.0).into_iter().enumerate() {
+    total_support += support(edges, refined[k], refined[(k + 1) % 4]);
+    let (start_line, end_line) = lines[k];
+    box_edges.push(BoxEdge { mid, points, start_line, end_line,
+                             width: None, threshold: None });
+  }
+  diag!("hough_finder: corners {:?}", refined);
+  // How much of the sides is really there in the edge map
+  Ok(orient_box(&refined, box_edges, options.aspect, total_support / 4.0))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::ColourType;
+
+  // An edge map with the pixels for which 'on' is true as edges
+  fn edge_map(width: usize, height: usize, on: &dyn Fn(f64, f64) -> bool) -> Image {
+    let mut edges = Image::new(width, height, ColourType::Grey, 255);
+    for y in 0..height {
+      for x in 0..width {
+        if on(x as f64, y as f64) {
+          edges[Point { x, y }] = 255;
+        }
+      }
+    }
+    edges
+  }
+
+  // The corners of a 'w' by 'h' rectangle centred on 'c' and turned
+  // 'degrees' clockwise, clockwise from the top left
+  fn rectangle(c: Pointf, w: f64, h: f64, degrees: f64) -> [Pointf; 4] {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let corner = |x: f64, y: f64| Pointf { x: c.x + x * cos - y * sin, y: c.y + x * sin + y * cos };
+    [corner(-w / 2.0, -h / 2.0), corner(w / 2.0, -h / 2.0), corner(w / 2.0, h / 2.0), corner(-w / 2.0, h / 2.0)]
+  }
+
+  #[test]
+  fn finds_rotated_rectangle() {
+    let (c, w, h, degrees) = (Pointf { x: 160.0, y: 120.0 }, 200.0, 100.0, 10.0f64);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    // A line two pixels wide just inside the rectangle, so its outside
+    // is the rectangle
+    let edges = edge_map(320, 240, &|x, y| {
+      let (dx, dy) = (x - c.x, y - c.y);
+      let (u, v) = ((dx * cos + dy * sin).abs(), (dy * cos - dx * sin).abs());
+      u <= w / 2.0 && v <= h / 2.0 && (u > w / 2.0 - 2.0 || v > h / 2.0 - 2.0)
+    });
+
+    let found = hough_finder(&edges, &FinderOptions::default()).unwrap();
+    assert!((found.rotation - degrees).abs() < 1.0, "rotation {}", found.rotation);
+    let corners = [found.top_left, found.top_right, found.bottom_right, found.bottom_left];
+    for (got, want) in corners.iter().zip(rectangle(c, w, h, degrees).iter()) {
+      assert!(got.distance(want) < 2.0, "corner {:?} should be {:?}", got, want);
+    }
+  }
+
+  #[test]
+  fn lines_that_make_no_box() {
+    // Four long horizontal lines; plenty of lines but no sides
+    let edges = edge_map(320, 240, &|_, y| y as usize % 50 == 20);
+    match hough_finder(&edges, &FinderOptions::default()) {
+      Err(BoxErr { edge: None, failure: Failure::NoSupportedBox(n) }) => assert!(n >= 4),
+      other => panic!("expected NoSupportedBox, got {:?}", other.map(|b| b.top_left)),
+    }
+  }
+
+  #[test]
+  fn blank_map_has_no_lines() {
+    let edges = edge_map(100, 80, &|_, _| false);
+    assert!(matches!(hough_finder(&edges, &FinderOptions::default()),
+                     Err(BoxErr { edge: None, failure: Failure::TooFewEdges(0) })));
+  }
+}
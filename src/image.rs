@@ -1,8 +1,10 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
 
+use std::fmt;
 use std::io;
 use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::prelude::*;
 use std::ops;
 use std::fs::File;
@@ -12,10 +14,69 @@ use point_line::*;
 
 // points in this image are indexed using (x: usize, y: usize) tuples
 // (0,0) is top left
+// Samples are held as u16 whatever the depth of the file; maxval is the
+// value that represents white.  Colour images hold the channels of each
+// pixel next to each other.
+#[derive(Clone)]
 pub struct Image {
   width: usize,
   height: usize,
-  data: Box<[u8]>,
+  colour: ColourType,
+  maxval: u16,
+  data: Box<[u16]>,
+}
+
+// The alpha variants hold the alpha as the last channel of each pixel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColourType {
+  Grey,
+  GreyAlpha,
+  Rgb,
+  RgbAlpha,
+}
+
+impl ColourType {
+  pub fn channels(self) -> usize {
+    match self {
+      ColourType::Grey      => 1,
+      ColourType::GreyAlpha => 2,
+      ColourType::Rgb       => 3,
+      ColourType::RgbAlpha  => 4,
+    }
+  }
+
+  // Number of channels excluding any alpha
+  pub fn colour_channels(self) -> usize {
+    match self {
+      ColourType::Grey | ColourType::GreyAlpha => 1,
+      ColourType::Rgb  | ColourType::RgbAlpha  => 3,
+    }
+  }
+
+  // The index of the alpha channel if there is one
+  pub fn alpha_channel(self) -> Option<usize> {
+    match self {
+      ColourType::GreyAlpha | ColourType::RgbAlpha => Some(self.channels() - 1),
+      _ => None,
+    }
+  }
+
+  pub fn with_alpha(self) -> ColourType {
+    match self {
+      ColourType::Grey | ColourType::GreyAlpha => ColourType::GreyAlpha,
+      ColourType::Rgb  | ColourType::RgbAlpha  => ColourType::RgbAlpha,
+    }
+  }
+
+  // The PAM TUPLTYPE name
+  pub fn tuple_type(self) -> &'static str {
+    match self {
+      ColourType::Grey      => "GRAYSCALE",
+      ColourType::GreyAlpha => "GRAYSCALE_ALPHA",
+      ColourType::Rgb       => "RGB",
+      ColourType::RgbAlpha  => "RGB_ALPHA",
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -32,85 +93,335 @@ impl From<io::Error> for ImageErr {
   }
 }
 
+impl fmt::Display for ImageErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ImageErr::IO(ref err) => write!(f, "IO error: {}", err),
+      ImageErr::NumErr => write!(f, "Bad number"),
+      ImageErr::BadHeader(ref s) => write!(f, "Bad header: {}", s),
+      ImageErr::WrongSubtype(ref s) => write!(f, "Unsupported type: {}", s),
+    }
+  }
+}
+
 /// Read the pnm header off a file
-/// Returns the type and then the (width, height)
-pub fn read_pnm_header(f: &mut BufRead) -> Result<(usize, (usize, usize)), ImageErr> {
+/// Returns the type, the (width, height), the maxval and then the colour type
+pub fn read_pnm_header(f: &mut dyn BufRead) -> Result<(usize, (usize, usize), u16, ColourType), ImageErr> {
   // Should start with 'P'
   let mut tmp_byte : [u8; 1] = [0; 1];
-  try!(f.read_exact(&mut tmp_byte));
+  f.read_exact(&mut tmp_byte)?;
 
-  if tmp_byte[0] != 'P' as u8 {
+  if tmp_byte[0] != b'P' {
     return Err(ImageErr::BadHeader(String::from("Missing PGM header P")));
   }
 
   // Next is a numeric character identifying the pnm subtype
-  try!(f.read_exact(&mut tmp_byte));
+  f.read_exact(&mut tmp_byte)?;
   if !(tmp_byte[0] as char).is_numeric() {
     return Err(ImageErr::BadHeader(String::from("Bad PGM header type")));
   }
-  let subtype = tmp_byte[0] - '0' as u8;
+  let subtype = tmp_byte[0] - b'0';
+
+  let colour = match subtype {
+    2 | 5 => ColourType::Grey,
+    3 | 6 => ColourType::Rgb,
+    // PAM format files are different from the other pnm and identify
+    // the fields with keywords not just raw figures
+    7 => return read_pam_header(f),
+    _ => return Err(ImageErr::WrongSubtype(format!("PNM type {} is not supported", subtype))),
+  };
 
-  // Note that PAM format files are different from the other pnm and identify
-  // the width/height fields with text not just raw figures, but I'm not dealing
-  // with that now
   let it = &mut f.bytes().peekable();
 
   // White space and the width
-  try!(skip_whitespace(it));
-  let width = try!(read_integer(it));
+  skip_whitespace(it)?;
+  let width = read_integer(it)?;
 
   // White space and then height
-  try!(skip_whitespace(it));
-  let height = try!(read_integer(it));
+  skip_whitespace(it)?;
+  let height = read_integer(it)?;
   
-  // White space and then max grey (We only support 255)
-  try!(skip_whitespace(it));
-  let max_grey = try!(read_integer(it));
-  if max_grey != 255 {
+  // White space and then max grey; anything above 255 means 2 bytes/sample
+  skip_whitespace(it)?;
+  let max_grey = read_integer(it)?;
+  if max_grey == 0 || max_grey > 65535 {
     return Err(ImageErr::BadHeader(String::from("Unsupported grey depth")));
   }
   // *1* white space and then data - note the peekable will have
   // consumed that white space even though read_integer didn't next it
 
-  Ok((subtype as usize, (width, height)))
+  Ok((subtype as usize, (width, height), max_grey as u16, colour))
+}
+
+/// Read the rest of a PAM header after the P7, a set of keyword/value lines
+/// terminated by ENDHDR
+fn read_pam_header(f: &mut dyn BufRead) -> Result<(usize, (usize, usize), u16, ColourType), ImageErr> {
+  let it = &mut f.bytes().peekable();
+
+  let mut width = None;
+  let mut height = None;
+  let mut depth = None;
+  let mut max_val = None;
+  let mut tuple_type = None;
+
+  loop {
+    skip_whitespace(it)?;
+    let keyword = read_word(it)?;
+    match keyword.as_str() {
+      "ENDHDR" => break,
+      "WIDTH"  => { skip_whitespace(it)?; width = Some(read_integer(it)?); },
+      "HEIGHT" => { skip_whitespace(it)?; height = Some(read_integer(it)?); },
+      "DEPTH"  => { skip_whitespace(it)?; depth = Some(read_integer(it)?); },
+      "MAXVAL" => { skip_whitespace(it)?; max_val = Some(read_integer(it)?); },
+      "TUPLTYPE" => { skip_whitespace(it)?; tuple_type = Some(read_word(it)?); },
+      _ => return Err(ImageErr::BadHeader(format!("Unknown PAM header keyword '{}'", keyword))),
+    }
+  }
+  // The newline after ENDHDR has been consumed by the peekable, leaving
+  // us at the start of the data
+
+  let (width, height, depth, max_val) = match (width, height, depth, max_val) {
+    (Some(w), Some(h), Some(d), Some(m)) => (w, h, d, m),
+    _ => return Err(ImageErr::BadHeader(String::from("PAM header missing WIDTH/HEIGHT/DEPTH/MAXVAL"))),
+  };
+  if max_val == 0 || max_val > 65535 {
+    return Err(ImageErr::BadHeader(String::from("Unsupported grey depth")));
+  }
+
+  let colour = match tuple_type.as_deref() {
+    Some("GRAYSCALE") | Some("BLACKANDWHITE") => ColourType::Grey,
+    Some("GRAYSCALE_ALPHA") | Some("BLACKANDWHITE_ALPHA") => ColourType::GreyAlpha,
+    Some("RGB") => ColourType::Rgb,
+    Some("RGB_ALPHA") => ColourType::RgbAlpha,
+    Some(t) => return Err(ImageErr::WrongSubtype(format!("PAM tuple type {} is not supported", t))),
+    // No tuple type; guess from the depth
+    None => match depth {
+      1 => ColourType::Grey,
+      2 => ColourType::GreyAlpha,
+      3 => ColourType::Rgb,
+      4 => ColourType::RgbAlpha,
+      _ => return Err(ImageErr::WrongSubtype(format!("PAM depth {} is not supported", depth))),
+    },
+  };
+  if colour.channels() != depth {
+    return Err(ImageErr::BadHeader(format!("PAM depth {} doesn't match tuple type {}", depth, colour.tuple_type())));
+  }
+
+  Ok((7, (width, height), max_val as u16, colour))
 }
 
 impl Image {
-  pub fn new(width: usize, height: usize) -> Image {
-    Image { width: width, height: height,
-            data: vec![0 as u8; width * height].into_boxed_slice() }
+  pub fn new(width: usize, height: usize, colour: ColourType, maxval: u16) -> Image {
+    Image { width, height, colour, maxval,
+            data: vec![0_u16; width * height * colour.channels()].into_boxed_slice() }
   }
 
-  pub fn load_pgm(file_name: &String) -> Result<Image, ImageErr> {
-    let mut r = BufReader::new(try!(File::open(file_name)));
-  
-    let (pnm_type, (my_width, my_height)) = try!(read_pnm_header(&mut r));
-  
-    match pnm_type {
-      2 => return Err(ImageErr::WrongSubtype(String::from("Plain PGM not supported"))),
-      5 => (), // that's normal binary PGM that we support
-      _ => return Err(ImageErr::WrongSubtype(format!("PNM type {} is not a PGM and is not supported", pnm_type))),
-    }
+  // Loads PGM (grey) and PPM (colour) files in either raw or plain format,
+  // and PAM files
+  pub fn load_pnm(file_name: &str) -> Result<Image, ImageErr> {
+    let mut r = BufReader::new(File::open(file_name)?);
+    Image::read_pnm(&mut r)
+  }
+
+  // Iterate over all the images in a file
+  pub fn load_pnm_frames(file_name: &str) -> Result<PnmFrames<BufReader<File>>, ImageErr> {
+    Ok(PnmFrames::new(BufReader::new(File::open(file_name)?)))
+  }
+
+  // As load_pnm but from any stream
+  pub fn read_pnm(r: &mut dyn BufRead) -> Result<Image, ImageErr> {
+    let (pnm_type, (my_width, my_height), my_maxval, colour) = read_pnm_header(r)?;
   
     if my_width == 0 || my_height == 0 {
       return Err(ImageErr::BadHeader(String::from("Width/height can't be 0")));
     }
   
-    let mut result = Image::new(my_width, my_height);
-    try!(r.read_exact(&mut result.data));
+    let mut result = Image::new(my_width, my_height, colour, my_maxval);
+    if pnm_type == 2 || pnm_type == 3 {
+      result.read_plain_data(r)?;
+    } else {
+      result.read_raw_data(r)?;
+    }
   
-    println!("Got pgm type {} {}x{}", pnm_type, my_width, my_height);
+    diag!("Got pnm type {} {}x{} maxval={}", pnm_type, my_width, my_height, my_maxval);
   
     Ok(result)
   }
 
-  pub fn save_pgm(&self, file_name: String) -> Result<(), ImageErr> {
-    let mut f = try!(File::create(file_name));
-    try!(write!(f, "P5\n{} {}\n255\n", self.width, self.height));
-    try!(f.write_all(&self.data));
+  // Bytes used by each sample in the raw formats
+  fn bytes_per_sample(&self) -> usize {
+    if self.maxval > 255 { 2 } else { 1 }
+  }
+
+  // The body of a raw PNM is 1 byte per sample, or 2 bytes big endian
+  // if the maxval is more than 255
+  fn read_raw_data(&mut self, f: &mut dyn BufRead) -> Result<(), ImageErr> {
+    let bps = self.bytes_per_sample();
+    let mut raw = vec![0_u8; self.data.len() * bps];
+    f.read_exact(&mut raw)?;
+
+    for (sample, bytes) in self.data.iter_mut().zip(raw.chunks(bps)) {
+      *sample = if bps == 2 {
+        (bytes[0] as u16) << 8 | bytes[1] as u16
+      } else {
+        bytes[0] as u16
+      };
+      if *sample > self.maxval {
+        return Err(ImageErr::BadHeader(format!("Sample value {} exceeds maximum", *sample)));
+      }
+    }
+
+    Ok(())
+  }
+
+  // The body of a plain PNM is whitespace separated decimal values, and
+  // comments are allowed anywhere in it
+  fn read_plain_data(&mut self, f: &mut dyn BufRead) -> Result<(), ImageErr> {
+    let it = &mut f.bytes().peekable();
+
+    for sample in self.data.iter_mut() {
+      skip_whitespace(it)?;
+      let value = read_integer(it)?;
+      if value > self.maxval as usize {
+        return Err(ImageErr::BadHeader(format!("Sample value {} exceeds maximum", value)));
+      }
+      *sample = value as u16;
+    }
+
+    Ok(())
+  }
+
+  // PNM type number for our colour type; the plain version is 3 less
+  // Only PAM can hold an alpha channel
+  fn pnm_type(&self) -> Result<usize, ImageErr> {
+    match self.colour {
+      ColourType::Grey => Ok(5),
+      ColourType::Rgb  => Ok(6),
+      _ => Err(ImageErr::WrongSubtype(format!("{} can only be saved as PAM", self.colour.tuple_type()))),
+    }
+  }
+
+  // The same layout is used for the body of raw PNM and PAM
+  fn write_raw_data(&self, f: &mut dyn Write) -> Result<(), ImageErr> {
+    let mut raw = Vec::with_capacity(self.data.len() * self.bytes_per_sample());
+    for sample in self.data.iter() {
+      if self.bytes_per_sample() == 2 {
+        raw.push((*sample >> 8) as u8);
+      }
+      raw.push(*sample as u8);
+    }
+    f.write_all(&raw)?;
+    Ok(())
+  }
+
+  // Saves as a raw PGM or PPM depending on the colour type
+  pub fn save_pnm(&self, file_name: &str) -> Result<(), ImageErr> {
+    let mut f = BufWriter::new(File::create(file_name)?);
+    self.write_pnm(&mut f)?;
+    f.flush()?;
+    Ok(())
+  }
+
+  pub fn write_pnm(&self, f: &mut dyn Write) -> Result<(), ImageErr> {
+    let pnm_type = self.pnm_type()?;
+    write!(f, "P{}\n{} {}\n{}\n", pnm_type, self.width, self.height, self.maxval)?;
+    self.write_raw_data(f)
+  }
+
+  pub fn save_pam(&self, file_name: &str) -> Result<(), ImageErr> {
+    let mut f = BufWriter::new(File::create(file_name)?);
+    self.write_pam(&mut f)?;
+    f.flush()?;
+    Ok(())
+  }
+
+  pub fn write_pam(&self, f: &mut dyn Write) -> Result<(), ImageErr> {
+    write!(f, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+           self.width, self.height, self.colour.channels(), self.maxval,
+           self.colour.tuple_type())?;
+    self.write_raw_data(f)
+  }
+
+  // Save as a plain (ASCII) PGM or PPM
+  pub fn save_plain_pnm(&self, file_name: &str) -> Result<(), ImageErr> {
+    let mut f = BufWriter::new(File::create(file_name)?);
+    self.write_plain_pnm(&mut f)?;
+    f.flush()?;
+    Ok(())
+  }
+
+  // One line per image row, wrapped to keep within the 70 character
+  // limit the format specifies
+  pub fn write_plain_pnm(&self, f: &mut dyn Write) -> Result<(), ImageErr> {
+    let pnm_type = self.pnm_type()? - 3;
+    write!(f, "P{}\n{} {}\n{}\n", pnm_type, self.width, self.height, self.maxval)?;
+
+    for row in self.data.chunks(self.width * self.colour.channels()) {
+      let mut line_len = 0;
+      for sample in row.iter() {
+        let text = sample.to_string();
+        if line_len > 0 && line_len + 1 + text.len() > 70 {
+          writeln!(f)?;
+          line_len = 0;
+        }
+        if line_len > 0 {
+          write!(f, " ")?;
+          line_len += 1;
+        }
+        write!(f, "{}", text)?;
+        line_len += text.len();
+      }
+      writeln!(f)?;
+    }
     Ok(())
   }
 
+  // The sample value representing white
+  pub fn get_maxval(&self) -> u16 {
+    self.maxval
+  }
+
+  pub fn get_colour(&self) -> ColourType {
+    self.colour
+  }
+
+  // Read channel 'c' of the pixel at 'p'
+  pub fn get(&self, p: Point, c: usize) -> u16 {
+    self.data[(p.x + p.y * self.width) * self.colour.channels() + c]
+  }
+
+  pub fn set(&mut self, p: Point, c: usize, value: u16) {
+    self.data[(p.x + p.y * self.width) * self.colour.channels() + c] = value;
+  }
+
+  // Produce a grey image of the brightness of this one using the
+  // Rec. 601 weights (as used by ppmtopgm); any alpha is dropped
+  pub fn luminance(&self) -> Image {
+    if self.colour == ColourType::Grey {
+      return self.clone();
+    }
+
+    let mut result = Image::new(self.width, self.height, ColourType::Grey, self.maxval);
+    for (out, pixel) in result.data.iter_mut().zip(self.data.chunks(self.colour.channels())) {
+      *out = if self.colour.colour_channels() == 1 {
+        pixel[0]
+      } else {
+        let lum = 0.299 * pixel[0] as f64 + 0.587 * pixel[1] as f64 + 0.114 * pixel[2] as f64;
+        lum.round() as u16
+      };
+    }
+    result
+  }
+
+  // Count of how many pixels have each value (0..maxval) in channel 'c'
+  pub fn histogram(&self, c: usize) -> Vec<usize> {
+    let mut counts = vec![0; self.maxval as usize + 1];
+    for pixel in self.data.chunks(self.colour.channels()) {
+      counts[pixel[c] as usize] += 1;
+    }
+    counts
+  }
+
   // TODO: This should be a trait for things that have a 2d size
   // The point returned is one past the edge of the image
   pub fn get_size(&self) -> Point {
@@ -118,16 +429,253 @@ impl Image {
   }
 }
 
+// Iterates over the images in a stream; the Netpbm formats allow any number
+// of images to be concatenated in one file
+pub struct PnmFrames<R: BufRead> {
+  r: R,
+  failed: bool,
+}
+
+impl<R: BufRead> PnmFrames<R> {
+  pub fn new(r: R) -> PnmFrames<R> {
+    PnmFrames { r, failed: false }
+  }
+
+  // Skip any whitespace between images (plain images normally end in
+  // a newline) and return true if there's another image to read
+  fn more(&mut self) -> Result<bool, ImageErr> {
+    loop {
+      let (len, white) = {
+        let buf = self.r.fill_buf()?;
+        (buf.len(), buf.iter().take_while(|b| (**b as char).is_whitespace()).count())
+      };
+      if len == 0 {
+        return Ok(false);
+      }
+      if white < len {
+        self.r.consume(white);
+        return Ok(true);
+      }
+      self.r.consume(len);
+    }
+  }
+}
+
+impl<R: BufRead> Iterator for PnmFrames<R> {
+  type Item = Result<Image, ImageErr>;
+
+  // Stops after the first error since we've lost our place in the stream
+  fn next(&mut self) -> Option<Result<Image, ImageErr>> {
+    if self.failed {
+      return None;
+    }
+    let result = match self.more() {
+      Ok(false) => return None,
+      Ok(true) => Image::read_pnm(&mut self.r),
+      Err(e) => Err(e),
+    };
+    self.failed = result.is_err();
+    Some(result)
+  }
+}
+
+// Indexing by point is only meaningful on grey images; use get/set
+// to access the channels of colour images
 impl ops::Index<Point> for Image {
-  type Output = u8;
+  type Output = u16;
 
-  fn index(&self, p: Point) -> &u8 {
+  fn index(&self, p: Point) -> &u16 {
+    debug_assert!(self.colour == ColourType::Grey);
     &self.data[p.x + p.y * self.width]
   }
 }
 
 impl ops::IndexMut<Point> for Image {
-  fn index_mut(&mut self, p: Point) -> &mut u8 {
+  fn index_mut(&mut self, p: Point) -> &mut u16 {
+    debug_assert!(self.colour == ColourType::Grey);
     &mut self.data[p.x + p.y * self.width]
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read(bytes: &[u8]) -> Result<Image, ImageErr> {
+    Image::read_pnm(&mut &bytes[..])
+  }
+
+  // Width, height, colour type and maxval
+  fn shape(i: &Image) -> (usize, usize, ColourType, u16) {
+    (i.width, i.height, i.colour, i.maxval)
+  }
+
+  // An image with the samples counting up, wrapping at maxval
+  fn ramp(width: usize, height: usize, colour: ColourType, maxval: u16) -> Image {
+    let mut i = Image::new(width, height, colour, maxval);
+    for (n, sample) in i.data.iter_mut().enumerate() {
+      *sample = (n * 37 % (maxval as usize + 1)) as u16;
+    }
+    i
+  }
+
+  #[test]
+  fn plain_with_comments() {
+    let i = read(b"P2\n# made by hand\n3 2 # size\n15\n0 1 2 # first row\n# a line on its own\n13#touching\n 14\n15\n").unwrap();
+    assert_eq!(shape(&i), (3, 2, ColourType::Grey, 15));
+    assert_eq!(&i.data[..], &[0, 1, 2, 13, 14, 15]);
+  }
+
+  #[test]
+  fn plain_rejects_bad_samples() {
+    assert!(matches!(read(b"P2\n2 1\n15\n3 16\n"), Err(ImageErr::BadHeader(_))));
+    assert!(matches!(read(b"P2\n2 1\n15\n3 x\n"), Err(ImageErr::NumErr)));
+  }
+
+  #[test]
+  fn plain_round_trip() {
+    for &colour in &[ColourType::Grey, ColourType::Rgb] {
+      let i = ramp(40, 3, colour, 255);
+      let mut out = Vec::new();
+      i.write_plain_pnm(&mut out).unwrap();
+      assert!(out.split(|&b| b == b'\n').all(|line| line.len() <= 70));
+      let j = read(&out).unwrap();
+      assert_eq!(shape(&j), shape(&i));
+      assert_eq!(j.data, i.data);
+    }
+  }
+
+  #[test]
+  fn raw_16_bit_is_big_endian() {
+    let i = read(b"P5\n2 1\n65535\n\x12\x34\xff\xfe").unwrap();
+    assert_eq!(shape(&i), (2, 1, ColourType::Grey, 65535));
+    assert_eq!(&i.data[..], &[0x1234, 0xfffe]);
+
+    let mut out = Vec::new();
+    i.write_pnm(&mut out).unwrap();
+    assert_eq!(&out[..], &b"P5\n2 1\n65535\n\x12\x34\xff\xfe"[..]);
+  }
+
+  #[test]
+  fn raw_rejects_samples_over_maxval() {
+    assert!(matches!(read(b"P5\n1 1\n1000\n\x03\xe9"), Err(ImageErr::BadHeader(_))));
+    assert!(matches!(read(b"P5\n1 1\n100\n\x65"), Err(ImageErr::BadHeader(_))));
+    assert!(matches!(read(b"P5\n1 1\n65536\n\x00\x00"), Err(ImageErr::BadHeader(_))));
+  }
+
+  #[test]
+  fn raw_round_trip() {
+    for &maxval in &[1, 100, 255, 256, 1000, 65535] {
+      for &colour in &[ColourType::Grey, ColourType::Rgb] {
+        let i = ramp(5, 4, colour, maxval);
+        let mut out = Vec::new();
+        i.write_pnm(&mut out).unwrap();
+        let j = read(&out).unwrap();
+        assert_eq!(shape(&j), shape(&i));
+        assert_eq!(j.data, i.data);
+      }
+    }
+  }
+
+  fn pam(header: &str, data: &[u8]) -> Result<Image, ImageErr> {
+    let mut bytes = format!("P7\n{}\nENDHDR\n", header).into_bytes();
+    bytes.extend_from_slice(data);
+    read(&bytes)
+  }
+
+  #[test]
+  fn pam_tuple_types() {
+    let i = pam("WIDTH 2\nHEIGHT 1\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA", &[1, 2, 3, 4]).unwrap();
+    assert_eq!(shape(&i), (2, 1, ColourType::GreyAlpha, 255));
+    assert_eq!(&i.data[..], &[1, 2, 3, 4]);
+
+    let i = pam("# keywords in any order\nTUPLTYPE RGB_ALPHA\nMAXVAL 255\nDEPTH 4\nHEIGHT 1\nWIDTH 1", &[1, 2, 3, 4]).unwrap();
+    assert_eq!(shape(&i), (1, 1, ColourType::RgbAlpha, 255));
+
+    let i = pam("WIDTH 1\nHEIGHT 1\nDEPTH 1\nMAXVAL 1\nTUPLTYPE BLACKANDWHITE", &[1]).unwrap();
+    assert_eq!(shape(&i), (1, 1, ColourType::Grey, 1));
+  }
+
+  #[test]
+  fn pam_depth_without_tuple_type() {
+    let i = pam("WIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255", &[1, 2, 3]).unwrap();
+    assert_eq!(shape(&i), (1, 1, ColourType::Rgb, 255));
+    assert!(matches!(pam("WIDTH 1\nHEIGHT 1\nDEPTH 5\nMAXVAL 255", &[0; 5]), Err(ImageErr::WrongSubtype(_))));
+  }
+
+  #[test]
+  fn pam_rejects_bad_headers() {
+    // Depth that doesn't match the tuple type
+    assert!(matches!(pam("WIDTH 1\nHEIGHT 1\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB", &[0; 4]), Err(ImageErr::BadHeader(_))));
+    assert!(matches!(pam("WIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nTUPLTYPE CMYK", &[0; 3]), Err(ImageErr::WrongSubtype(_))));
+    assert!(matches!(pam("WIDTH 1\nHEIGHT 1\nMAXVAL 255\nTUPLTYPE RGB", &[0; 3]), Err(ImageErr::BadHeader(_))));
+    assert!(matches!(pam("WIDTH 1\nHEIGHT 1\nDEPTH 3\nMAXVAL 255\nCOLOUR RGB", &[0; 3]), Err(ImageErr::BadHeader(_))));
+  }
+
+  #[test]
+  fn pam_round_trip() {
+    for &colour in &[ColourType::Grey, ColourType::GreyAlpha, ColourType::Rgb, ColourType::RgbAlpha] {
+      for &maxval in &[255, 65535] {
+        let i = ramp(3, 2, colour, maxval);
+        let mut out = Vec::new();
+        i.write_pam(&mut out).unwrap();
+        let j = read(&out).unwrap();
+        assert_eq!(shape(&j), shape(&i));
+        assert_eq!(j.data, i.data);
+      }
+    }
+  }
+
+  #[test]
+  fn alpha_only_saved_as_pam() {
+    let i = ramp(1, 1, ColourType::GreyAlpha, 255);
+    assert!(matches!(i.write_pnm(&mut Vec::new()), Err(ImageErr::WrongSubtype(_))));
+    assert!(matches!(i.write_plain_pnm(&mut Vec::new()), Err(ImageErr::WrongSubtype(_))));
+  }
+
+  #[test]
+  fn frames_of_mixed_formats() {
+    let images = [ramp(4, 3, ColourType::Grey, 255), ramp(2, 2, ColourType::Rgb, 1000),
+                  ramp(3, 1, ColourType::Grey, 15), ramp(2, 3, ColourType::RgbAlpha, 255)];
+    let mut stream = Vec::new();
+    images[0].write_plain_pnm(&mut stream).unwrap();
+    images[1].write_pnm(&mut stream).unwrap();
+    // Extra whitespace between images is skipped
+    stream.extend_from_slice(b"\n \n");
+    images[2].write_plain_pnm(&mut stream).unwrap();
+    images[3].write_pam(&mut stream).unwrap();
+    stream.extend_from_slice(b"\n");
+
+    let frames : Vec<Image> = PnmFrames::new(&stream[..]).map(|f| f.unwrap()).collect();
+    assert_eq!(frames.len(), images.len());
+    for (frame, image) in frames.iter().zip(images.iter()) {
+      assert_eq!(shape(frame), shape(image));
+      assert_eq!(frame.data, image.data);
+    }
+  }
+
+  #[test]
+  fn frames_stop_after_an_error() {
+    let mut stream = Vec::new();
+    ramp(2, 2, ColourType::Grey, 255).write_pnm(&mut stream).unwrap();
+    // A bad second image, then one we shouldn't get to
+    stream.extend_from_slice(b"P9\n2 2\n255\n");
+    ramp(2, 2, ColourType::Grey, 255).write_pnm(&mut stream).unwrap();
+
+    let mut frames = PnmFrames::new(&stream[..]);
+    assert!(frames.next().unwrap().is_ok());
+    assert!(matches!(frames.next(), Some(Err(ImageErr::WrongSubtype(_)))));
+    assert!(frames.next().is_none());
+
+    // A truncated last image
+    let mut frames = PnmFrames::new(&b"P5\n2 2\n255\n\x01\x02"[..]);
+    assert!(matches!(frames.next(), Some(Err(ImageErr::IO(_)))));
+    assert!(frames.next().is_none());
+  }
+
+  #[test]
+  fn frames_of_empty_stream() {
+    assert!(PnmFrames::new(&b""[..]).next().is_none());
+    assert!(PnmFrames::new(&b" \n"[..]).next().is_none());
+  }
+}
@@ -0,0 +1,38 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET : AtomicBool = AtomicBool::new(false);
+
+// Stop the library printing its progress information to stderr
+pub fn set_quiet(quiet: bool) {
+  QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+  QUIET.load(Ordering::Relaxed)
+}
+
+// eprintln! unless we've been asked to be quiet
+macro_rules! diag {
+  ($($arg:tt)*) => {
+    if !$crate::quiet() {
+      eprintln!($($arg)*);
+    }
+  }
+}
+
+pub mod box_finder;
+pub mod contrast;
+pub mod curve;
+pub mod edges;
+pub mod filter;
+pub mod hough;
+pub mod image;
+pub mod line_fit;
+pub mod point_line;
+pub mod sampler;
+pub mod string;
+pub mod threshold;
+pub mod transform;
@@ -0,0 +1,227 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Fitting straight lines to noisy sets of points
+
+use std::f64;
+
+use point_line::Pointf;
+
+// A fitted line passing through 'point' in the (unit length) direction 'dir'
+#[derive(Copy, Clone, Debug)]
+pub struct LineFit {
+  pub point: Pointf,
+  pub dir: Pointf,
+  // RMS perpendicular distance of the points used in the fit
+  pub residual: f64,
+  // The number of points used, and the fraction of all the points that is
+  pub inliers: usize,
+  pub confidence: f64,
+}
+
+impl LineFit {
+  // Perpendicular distance of 'p' from the line
+  pub fn distance(&self, p: Pointf) -> f64 {
+    ((p.x - self.point.x) * self.dir.y - (p.y - self.point.y) * self.dir.x).abs()
+  }
+
+  // Where this line crosses 'other', None if they're (nearly) parallel
+  pub fn intersection(&self, other: &LineFit) -> Option<Pointf> {
+    let denom = self.dir.x * other.dir.y - self.dir.y * other.dir.x;
+    if denom.abs() < 1e-9 {
+      return None;
+    }
+    let dx = other.point.x - self.point.x;
+    let dy = other.point.y - self.point.y;
+    let t = (dx * other.dir.y - dy * other.dir.x) / denom;
+
+    Some(Pointf { x: self.point.x + t * self.dir.x,
+                  y: self.point.y + t * self.dir.y })
+  }
+}
+
+// Total least squares (orthogonal regression) fit; unlike a y-on-x
+// regression this minimises the perpendicular distance so it copes with
+// vertical lines.  Needs at least two distinct points.
+pub fn total_least_squares(points: &[Pointf]) -> Option<LineFit> {
+  if points.len() < 2 {
+    return None;
+  }
+  let n = points.len() as f64;
+  let mean_x = points.iter().map(|p| p.x).sum::<f64>() / n;
+  let mean_y = points.iter().map(|p| p.y).sum::<f64>() / n;
+
+  let mut sxx = 0.0;
+  let mut syy = 0.0;
+  let mut sxy = 0.0;
+  for p in points {
+    let dx = p.x - mean_x;
+    let dy = p.y - mean_y;
+    sxx += dx * dx;
+    syy += dy * dy;
+    sxy += dx * dy;
+  }
+  if sxx + syy == 0.0 {
+    return None;
+  }
+
+  // The direction is the principal eigenvector of the covariance matrix
+  let angle = 0.5 * (2.0 * sxy).atan2(sxx - syy);
+  let mut fit = LineFit { point: Pointf { x: mean_x, y: mean_y },
+                          dir: Pointf { x: angle.cos(), y: angle.sin() },
+                          residual: 0.0,
+                          inliers: points.len(),
+                          confidence: 1.0 };
+  let sum_sq = points.iter().map(|p| fit.distance(*p).powi(2)).sum::<f64>();
+  fit.residual = (sum_sq / n).sqrt();
+  Some(fit)
+}
+
+// A small xorshift generator; we want RANSAC to be repeatable from run
+// to run rather than truly random
+struct XorShift(u64);
+
+impl XorShift {
+  fn next(&mut self, limit: usize) -> usize {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    (self.0 % limit as u64) as usize
+  }
+}
+
+// Fit a line ignoring outliers: try lines through random pairs of points
+// and keep the one with the most points within 'threshold' of it, then
+// do a least squares fit through those inliers
+pub fn ransac(points: &[Pointf], threshold: f64, iterations: usize) -> Option<LineFit> {
+  if points.len() < 2 {
+    return None;
+  }
+  let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+  let mut best_count = 0;
+  let mut best_error = f64::MAX;
+  let mut best_line = None;
+  for _ in 0..iterations {
+    let a = points[rng.next(points.len())];
+    let b = points[rng.next(points.len())];
+    let len = ((b.x - a.x).powi(2) + (b.y - a.y).powi(2)).sqrt();
+    if len == 0.0 {
+      continue;
+    }
+    let candidate = LineFit { point: a,
+                              dir: Pointf { x: (b.x - a.x) / len, y: (b.y - a.y) / len },
+                              residual: 0.0, inliers: 0, confidence: 0.0 };
+
+    let mut count = 0;
+    let mut error = 0.0;
+    for p in points {
+      let d = candidate.distance(*p);
+      if d <= threshold {
+        count += 1;
+        error += d;
+      }
+    }
+    if count > best_count || (count == best_count && error < best_error) {
+      best_count = count;
+      best_error = error;
+      best_line = Some(candidate);
+    }
+  }
+
+  let best_line = best_line?;
+  let inliers : Vec<Pointf> = points.iter().cloned()
+                                    .filter(|p| best_line.distance(*p) <= threshold)
+                                    .collect();
+  let mut fit = total_least_squares(&inliers)?;
+  fit.confidence = inliers.len() as f64 / points.len() as f64;
+  Some(fit)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn pt(x: f64, y: f64) -> Pointf {
+    Pointf { x, y }
+  }
+
+  // 'fit' runs in direction 'dir' (either way along it) through 'on'
+  fn assert_line(fit: &LineFit, on: Pointf, dir: Pointf) {
+    let len = (dir.x * dir.x + dir.y * dir.y).sqrt();
+    let cross = (fit.dir.x * dir.y - fit.dir.y * dir.x) / len;
+    assert!(cross.abs() < 1e-9, "{:?} isn't along {:?}", fit, dir);
+    assert!(fit.distance(on) < 1e-9, "{:?} doesn't pass through {:?}", fit, on);
+  }
+
+  // 'count' points along y = x/2 + 3, and along x = 7
+  fn sloped(count: usize) -> Vec<Pointf> {
+    (0..count).map(|k| pt(k as f64 * 2.0, k as f64 + 3.0)).collect()
+  }
+
+  fn vertical(count: usize) -> Vec<Pointf> {
+    (0..count).map(|k| pt(7.0, k as f64 * 1.5 - 10.0)).collect()
+  }
+
+  // Points well off both lines
+  fn outliers() -> Vec<Pointf> {
+    (0..10).map(|k| pt(k as f64 * 5.0 + 20.0, (k * 7 % 10) as f64 * 9.0 - 40.0)).collect()
+  }
+
+  #[test]
+  fn least_squares_on_exact_lines() {
+    let fit = total_least_squares(&sloped(20)).unwrap();
+    assert_line(&fit, pt(0.0, 3.0), pt(2.0, 1.0));
+    assert!(fit.residual < 1e-9);
+    assert_eq!(fit.inliers, 20);
+
+    let fit = total_least_squares(&vertical(20)).unwrap();
+    assert_line(&fit, pt(7.0, 0.0), pt(0.0, 1.0));
+  }
+
+  #[test]
+  fn least_squares_residual() {
+    // Alternately half a pixel either side of y = 4
+    let points : Vec<Pointf> = (0..20).map(|k| pt(k as f64, if k % 2 == 0 { 4.5 } else { 3.5 })).collect();
+    let fit = total_least_squares(&points).unwrap();
+    assert!(fit.dir.y.abs() < 0.01);
+    assert!((fit.point.y - 4.0).abs() < 1e-9);
+    assert!((fit.residual - 0.5).abs() < 0.01);
+  }
+
+  #[test]
+  fn ransac_ignores_outliers() {
+    for (line, on, dir) in [(sloped(30), pt(0.0, 3.0), pt(2.0, 1.0)),
+                            (vertical(30), pt(7.0, 0.0), pt(0.0, 1.0))] {
+      let mut points = line;
+      points.extend(outliers());
+      let fit = ransac(&points, 1.0, 200).unwrap();
+      assert_line(&fit, on, dir);
+      assert_eq!(fit.inliers, 30);
+      assert!((fit.confidence - 0.75).abs() < 1e-9);
+
+      // Whereas they pull a plain least squares fit off the line
+      let plain = total_least_squares(&points).unwrap();
+      assert!(plain.distance(on) > 1.0 || (plain.dir.x * dir.y - plain.dir.y * dir.x).abs() > 0.1);
+    }
+  }
+
+  #[test]
+  fn too_few_points() {
+    assert!(total_least_squares(&[]).is_none());
+    assert!(total_least_squares(&[pt(1.0, 2.0)]).is_none());
+    assert!(total_least_squares(&[pt(1.0, 2.0), pt(1.0, 2.0)]).is_none());
+    assert!(ransac(&[], 1.0, 200).is_none());
+    assert!(ransac(&[pt(1.0, 2.0)], 1.0, 200).is_none());
+    assert!(ransac(&[pt(1.0, 2.0), pt(1.0, 2.0), pt(1.0, 2.0)], 1.0, 200).is_none());
+  }
+
+  #[test]
+  fn intersection() {
+    let a = total_least_squares(&sloped(5)).unwrap();
+    let b = total_least_squares(&vertical(5)).unwrap();
+    let p = a.intersection(&b).unwrap();
+    assert!((p.x - 7.0).abs() < 1e-9 && (p.y - 6.5).abs() < 1e-9);
+    assert!(a.intersection(&a).is_none());
+  }
+}
@@ -1,112 +1,306 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+extern crate rustimage;
+
+mod options;
 
 use std::env;
+use std::io;
 use std::io::prelude::*;
+use std::io::BufReader;
+use std::io::BufWriter;
 use std::fs::File;
+use std::process;
 
-mod box_finder;
-mod curve;
-mod image;
-mod point_line;
-mod string;
-mod transform;
+use rustimage::box_finder;
+use rustimage::curve;
+use rustimage::curve::Curve;
+use rustimage::edges::EdgeMap;
+use rustimage::hough;
+use rustimage::point_line;
+use rustimage::transform;
+use rustimage::transform::Mapping;
+use rustimage::point_line::*;
+use rustimage::image::*;
 
-use point_line::*;
-use image::*;
+use options::{Detector, Options, RatioCheck};
 
-fn plot_svg_line(f: &mut File, l: &point_line::Line, style: &str) -> Result<(), std::io::Error> {
+// Number of segments used to measure the length of an edge curve
+const BEZ_LENGTH_STEPS : usize = 50;
+
+fn plot_svg_line(f: &mut File, l: &point_line::Linef, style: &str) -> Result<(), std::io::Error> {
   let (p1, p2) = *l;
   writeln!(f, "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" style=\"{}\" />",
            p1.x, p1.y, p2.x, p2.y, style)
 }
 
-fn plot_svg_our_bez(f: &mut File, bez: &curve::Bezierq, steps: usize, style: &str) -> Result<(), std::io::Error> {
-  let mut prev = Point::from(bez.interp(0.0));
+fn plot_svg_our_bez(f: &mut File, bez: &dyn Curve, steps: usize, style: &str) -> Result<(), std::io::Error> {
+  let mut prev = bez.interp(0.0);
 
   for p in 1..(steps+1) {
     let t = p as f64 / steps as f64;
-    let cur = Point::from(bez.interp(t));
+    let cur = bez.interp(t);
 
-    try!(plot_svg_line(f, &(prev, cur), style));
+    plot_svg_line(f, &(prev, cur), style)?;
     prev = cur;
   }
   Ok(())
 }
 
-fn main() {
-  let mut our_args = env::args();
+// Images with alpha can only be written as PAM
+fn write_output(image: &Image, f: &mut dyn Write, plain: bool) -> Result<(), ImageErr> {
+  if image.get_colour().alpha_channel().is_some() {
+    image.write_pam(f)
+  } else if plain {
+    image.write_plain_pnm(f)
+  } else {
+    image.write_pnm(f)
+  }
+}
+
+// Draw the detected box over the input image, or on its own if the input
+// has no file name to refer to
+fn write_debug_svg(svg_name: &str, file_name: Option<&str>, image_size: Point,
+                   lines: &[Linef], curves: &[&dyn Curve]) -> Result<(), std::io::Error> {
+  let mut svgf = File::create(svg_name)?;
+  writeln!(svgf, "<svg height=\"{}px\" width=\"{}px\" xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\">", image_size.y, image_size.x)?;
+  if let Some(file_name) = file_name {
+    writeln!(svgf, "  <image xlink:href=\"{}\" x=\"0\" y=\"0\" width=\"{}px\" height=\"{}px\"/>", file_name, image_size.x, image_size.y)?;
+  }
+
+  let cyan_1_style="stroke:rgb(0,255,255);stroke-width:1";
+  for l in lines {
+    plot_svg_line(&mut svgf, l, cyan_1_style)?;
+  }
+  let orange_1_style="stroke:rgb(255,138,0);stroke-width:1";
+  for bez in curves {
+    plot_svg_our_bez(&mut svgf, *bez, 10, orange_1_style)?;
+  }
 
-  if our_args.len() != 2 {
-    panic!("expected one arg but got {}", our_args.len());
+  writeln!(svgf, "</svg>")
+}
+
+// Debug files for frames after the first get the frame number added
+// before the extension, e.g. debug.svg, debug-1.svg, debug-2.svg
+fn frame_file_name(name: &str, frame: usize) -> String {
+  if frame == 0 {
+    return String::from(name);
   }
-  let file_name = our_args.nth(1).unwrap();
-  println!("Filename = {}", file_name);
+  match name.rfind('.') {
+    Some(dot) if !name[dot..].contains('/') =>
+      format!("{}-{}{}", &name[..dot], frame, &name[dot..]),
+    _ => format!("{}-{}", name, frame),
+  }
+}
 
-  let in_image = Image::load_pgm(&file_name).unwrap();
+// Find the rectangle in one image (the 'frame'th in the input) and produce
+// the straightened version, writing any debug files asked for
+fn straighten(in_image: &Image, options: &Options, frame: usize) -> Result<Image, String> {
   let image_size = in_image.get_size();
 
-  let (top_left, top_edge_mid, top_right,
-                 right_edge_mid, bottom_right,
-                 bottom_edge_mid, bottom_left,
-                 left_edge_mid) = box_finder::box_finder(&in_image);
+  // We search a grey copy; the output is sampled from the original
+  let mut detect_image = in_image.luminance();
+  if let Some(denoise) = options.denoise {
+    detect_image = denoise.apply(&detect_image);
+  }
+  if let Some(enhance) = options.detect_enhance {
+    detect_image = enhance.apply(&detect_image);
+  }
+  // The Hough detector works on the edge map rather than the image
+  let edge_map = if options.save_edges.is_some() || options.detector != Detector::Walk {
+    Some(options.detect_edges.unwrap_or(EdgeMap::Canny).apply(&detect_image))
+  } else {
+    None
+  };
+  if let (Some(name), Some(map)) = (options.save_edges.as_ref(), edge_map.as_ref()) {
+    let name = frame_file_name(name, frame);
+    if let Err(e) = map.save_pnm(&name) {
+      eprintln!("Failed to write {}: {}", name, e);
+    }
+  }
+  let walk = || {
+    match options.detect_edges {
+      Some(edges) => box_finder::box_finder(&edges.for_detection(&detect_image), &options.finder),
+      None => box_finder::box_finder(&detect_image, &options.finder),
+    }
+  };
+  let hough = || hough::hough_finder(edge_map.as_ref().unwrap(), &options.finder);
 
-  let mut out_image = Image::new(4000,2000); // TODO: Make size configurable
+  let found = match options.detector {
+    Detector::Walk => walk(),
+    Detector::Hough => hough(),
+    Detector::Auto => walk().or_else(|e| {
+      if !options.quiet {
+        eprintln!("{}; trying the Hough detector", e);
+      }
+      hough()
+    }),
+  }.map_err(|e| e.to_string())?;
 
-  let hdistance = left_edge_mid.distance(&right_edge_mid);
-  let vdistance = top_edge_mid.distance(&bottom_edge_mid);
+  let out_colour = if options.alpha {
+    in_image.get_colour().with_alpha()
+  } else {
+    in_image.get_colour()
+  };
+
+  let (top_bez, top_fit) = found.top_curve(options.edge_model);
+  let (bottom_bez, bottom_fit) = found.bottom_curve(options.edge_model);
+  let (left_bez, left_fit) = found.left_curve(options.edge_model);
+  let (right_bez, right_fit) = found.right_curve(options.edge_model);
+
+  let top_mid = top_bez.interp(0.5);
+  let bottom_mid = bottom_bez.interp(0.5);
+  let left_mid = left_bez.interp(0.5);
+  let right_mid = right_bez.interp(0.5);
+  let hdistance = left_mid.distance(&right_mid);
+  let vdistance = top_mid.distance(&bottom_mid);
 
   let ratio = hdistance/vdistance;
 
-  println!("points tl/tr/br/bl={:?}/{:?}/{:?}/{:?}", top_left, top_right, bottom_right, bottom_left);
-  println!("hdistance={} vdistance={} ratio={}", hdistance, vdistance, ratio);
-
-  let hline = (left_edge_mid, right_edge_mid);
-  let vline = (top_edge_mid, bottom_edge_mid);
-
-  let left_bez = curve::Bezierq::through(Pointf::from(top_left),
-                                        Pointf::from(left_edge_mid),
-                                        Pointf::from(bottom_left),
-                                        0.5);
-  let right_bez = curve::Bezierq::through(Pointf::from(top_right),
-                                        Pointf::from(right_edge_mid),
-                                        Pointf::from(bottom_right),
-                                        0.5);
-  // Hmm I'm not confident about this choice of the midpoint
-  let midpoint = line_intersection(&hline, &vline);
-  let midv_bez = curve::Bezierq::through(Pointf::from(top_edge_mid),
-                                        Pointf::from(midpoint),
-                                        Pointf::from(bottom_edge_mid),
-                                        0.5);
-
-  transform::transform(&in_image, &mut out_image,
-                       &left_bez, &midv_bez, &right_bez);
-
-  out_image.save_pgm(format!("debug.pgm")).unwrap();
-
-  let mut svgf = File::create("debug.svg").unwrap();
-  writeln!(svgf, "<svg height=\"{}px\" width=\"{}px\" xmlns=\"http://www.w3.org/2000/svg\" xmlns:xlink=\"http://www.w3.org/1999/xlink\">", image_size.x, image_size.y).unwrap();
-  writeln!(svgf, "  <image xlink:href=\"{}\" x=\"0\" y=\"0\" width=\"{}px\" height=\"{}px\"/>", file_name, image_size.x, image_size.y).unwrap();
-
-  let top_bez = curve::Bezierq::through(Pointf::from(top_left),
-                                        Pointf::from(top_edge_mid),
-                                        Pointf::from(top_right),
-                                        0.5);
-  let bottom_bez = curve::Bezierq::through(Pointf::from(bottom_left),
-                                        Pointf::from(bottom_edge_mid),
-                                        Pointf::from(bottom_right),
-                                        0.5);
+  if !options.quiet {
+    eprintln!("points tl/tr/br/bl={:?}/{:?}/{:?}/{:?}", found.top_left, found.top_right, found.bottom_right, found.bottom_left);
+    eprintln!("hdistance={} vdistance={} ratio={} confidence={}", hdistance, vdistance, ratio, found.confidence);
+    for (name, fit) in &[("top", top_fit), ("right", right_fit), ("bottom", bottom_fit), ("left", left_fit)] {
+      eprintln!("{} edge {:?} fit: {} points rms={:.3} max={:.3}", name, options.edge_model, fit.points, fit.rms, fit.max);
+    }
+  }
+
+  let hline = (left_mid, right_mid);
+  let vline = (top_mid, bottom_mid);
 
-  let cyan_1_style="stroke:rgb(0,255,255);stroke-width:1";
-  plot_svg_line(&mut svgf, &hline, cyan_1_style).unwrap();
-  plot_svg_line(&mut svgf, &vline, cyan_1_style).unwrap();
-  let orange_1_style="stroke:rgb(255,138,0);stroke-width:1";
-  plot_svg_our_bez(&mut svgf, &top_bez, 10, orange_1_style).unwrap();
-  plot_svg_our_bez(&mut svgf, &bottom_bez, 10, orange_1_style).unwrap();
-  plot_svg_our_bez(&mut svgf, &left_bez, 10, orange_1_style).unwrap();
-  plot_svg_our_bez(&mut svgf, &right_bez, 10, orange_1_style).unwrap();
+  // The middle of the surface bounded by all four edges, so it bows to
+  // match the top and bottom as well as the sides
+  let patch = curve::Coons::new(&*top_bez, &*bottom_bez, &*left_bez, &*right_bez);
+  let midv_bez = patch.column(0.5);
 
-  writeln!(svgf, "</svg>").unwrap();
+  // The edges may be bowed, so measure along them rather than across
+  let measured = ((top_bez.length(BEZ_LENGTH_STEPS) + bottom_bez.length(BEZ_LENGTH_STEPS)) / 2.0,
+                  (left_bez.length(BEZ_LENGTH_STEPS) + right_bez.length(BEZ_LENGTH_STEPS)) / 2.0);
+  let (out_width, out_height) = options.output_size(measured);
+  if !options.quiet {
+    eprintln!("edge lengths h/v={}/{} output={}x{}", measured.0, measured.1, out_width, out_height);
+  }
+  if let Err(e) = options.check_ratio(measured.0 / measured.1) {
+    if options.ratio_check == RatioCheck::Fail {
+      return Err(format!("Rectangle is the wrong shape: {}", e));
+    }
+    eprintln!("Warning: {}; the rectangle may have been misdetected", e);
+  }
+  let mut out_image = Image::new(out_width, out_height, out_colour, in_image.get_maxval());
+
+  match options.mapping {
+    Mapping::Rows => transform::transform(in_image, &mut out_image,
+                                          &*left_bez, &midv_bez, &*right_bez,
+                                          options.sampler),
+    Mapping::Coons => transform::transform_patch(in_image, &mut out_image, &patch, options.sampler),
+  }
+  if let Some(enhance) = options.output_enhance {
+    out_image = enhance.apply(&out_image);
+  }
+
+  if let Some(ref svg_name) = options.debug_svg {
+    let svg_name = frame_file_name(svg_name, frame);
+    // There's no file for the SVG to show when we read stdin
+    let input = if options.input == "-" { None } else { Some(options.input.as_str()) };
+    // A broken debug file isn't worth losing the real output for
+    if let Err(e) = write_debug_svg(&svg_name, input, image_size, &[hline, vline],
+                                    &[&*top_bez, &*bottom_bez, &*left_bez, &*right_bez, &midv_bez]) {
+      eprintln!("Failed to write {}: {}", svg_name, e);
+    }
+  }
+
+  Ok(out_image)
+}
+
+fn open_output(name: &str) -> Result<Box<dyn Write>, std::io::Error> {
+  if name == "-" {
+    Ok(Box::new(BufWriter::new(io::stdout())))
+  } else {
+    Ok(Box::new(BufWriter::new(File::create(name)?)))
+  }
+}
+
+// Straighten every image in the input; returns the number of images that
+// couldn't be processed, or an error that stops us entirely
+fn run(options: &Options) -> Result<usize, String> {
+  if !options.quiet {
+    eprintln!("Filename = {}", options.input);
+  }
+
+  let stdin = io::stdin();
+  let input : Box<dyn BufRead> = if options.input == "-" {
+    Box::new(stdin.lock())
+  } else {
+    let f = File::open(&options.input).map_err(|e| format!("Failed to open {}: {}", options.input, e))?;
+    Box::new(BufReader::new(f))
+  };
+
+  // Outputs are opened as they're first needed; without a name given each
+  // image goes to out.pgm, out.ppm or out.pam to suit its colour type
+  let mut outputs : Vec<(String, Box<dyn Write>)> = Vec::new();
+  let mut failed_frames = 0;
+
+  for (frame, in_image) in PnmFrames::new(input).enumerate() {
+    let in_image = in_image.map_err(|e| format!("Failed to read image {} of {}: {}", frame, options.input, e))?;
+
+    let out_image = match straighten(&in_image, options, frame) {
+      Ok(image) => image,
+      Err(e) => {
+        eprintln!("Skipping image {}: {}", frame, e);
+        failed_frames += 1;
+        continue;
+      },
+    };
+
+    let out_name = match options.output {
+      Some(ref name) => name.clone(),
+      None => String::from(match out_image.get_colour() {
+        ColourType::Grey => "out.pgm",
+        ColourType::Rgb  => "out.ppm",
+        _ => "out.pam",
+      }),
+    };
+    let index = match outputs.iter().position(|o| o.0 == out_name) {
+      Some(index) => index,
+      None => {
+        let f = open_output(&out_name).map_err(|e| format!("Failed to create {}: {}", out_name, e))?;
+        outputs.push((out_name, f));
+        outputs.len() - 1
+      },
+    };
+    let (ref out_name, ref mut f) = outputs[index];
+    write_output(&out_image, f, options.plain).map_err(|e| format!("Failed to write {}: {}", out_name, e))?;
+  }
 
+  for (out_name, mut f) in outputs {
+    f.flush().map_err(|e| format!("Failed to write {}: {}", out_name, e))?;
+  }
+  Ok(failed_frames)
 }
 
+fn main() {
+  let args : Vec<String> = env::args().skip(1).collect();
+  let options = match Options::parse(&args) {
+    Ok(options) => options,
+    Err(e) => {
+      eprintln!("rustimage: {}", e);
+      eprint!("{}", options::USAGE);
+      process::exit(2);
+    },
+  };
+  if options.help {
+    print!("{}", options::USAGE);
+    return;
+  }
+  rustimage::set_quiet(options.quiet);
+
+  match run(&options) {
+    Ok(0) => (),
+    Ok(_) => process::exit(1),
+    Err(e) => {
+      eprintln!("rustimage: {}", e);
+      process::exit(1);
+    },
+  }
+}
@@ -0,0 +1,361 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Command line parsing for the rustimage binary
+
+use std::str::FromStr;
+
+use rustimage::box_finder::{FinderOptions, ThresholdMode};
+use rustimage::contrast::Enhance;
+use rustimage::curve::EdgeModel;
+use rustimage::edges::EdgeMap;
+use rustimage::filter::Denoise;
+use rustimage::sampler::Sampler;
+use rustimage::transform::Mapping;
+
+pub const USAGE : &str = "\
+Usage: rustimage [OPTIONS] INPUT [OUTPUT]
+
+Finds the black rectangle in each image of INPUT (PGM/PPM/PAM) and writes
+the straightened contents to OUTPUT.  '-' reads stdin or writes stdout.
+
+Options:
+  -o, --output PATH     Output file (default out.pgm, out.ppm or out.pam
+                        to suit each image)
+  -W, --width N         Output width in pixels
+  -H, --height N        Output height in pixels
+  -s, --scale F         Multiply the output size by F
+      --auto-size       Size the output from the rectangle's edge lengths,
+                        keeping the input's pixel density
+  -a, --aspect W:H      Aspect ratio of the rectangle, e.g. 1:1, 3:2 or
+                        1:1.414 (default 2:1); sets the missing dimension
+                        if only one is given
+      --ratio-check MODE  What to do when the measured aspect ratio is
+                        wrong: warn (default), fail or off
+      --ratio-tolerance F Allowed fractional error in the measured aspect
+                        ratio (default 0.1)
+      --detector NAME   How to find the rectangle: walk (default) follows
+                        the line round from where it's first found, hough
+                        looks for the four strongest straight lines in an
+                        edge map, auto tries hough if walk fails
+      --search MODE     Where to look for the rectangle: centre (default)
+                        scans in from the middle of each side, only trying
+                        further along when that doesn't find all four
+                        edges; multi always scans from several points
+      --threshold MODE  How to tell the dark line from the paper: scan
+                        (default) uses the pixels along each scan, otsu a
+                        single level for the image, sauvola an adaptive
+                        level for uneven lighting
+      --denoise MODE    Filter the copy used to find the rectangle with
+                        gaussian:RADIUS or median:RADIUS
+      --detect-edges MODE  Search an edge map (sobel or canny) of the image
+                        rather than the image itself; this finds the outside
+                        of the line, and implies --threshold otsu
+      --save-edges PATH Write the edge map (canny unless --detect-edges
+                        says otherwise)
+      --detect-enhance MODE  Enhance the contrast of the copy used to find
+                        the rectangle: stretch[:PERCENT] clips PERCENT
+                        (default 0.5) at each end, clahe[:LIMIT] is adaptive
+                        equalisation (default limit 2)
+      --output-enhance MODE  The same for the straightened output
+      --edge-model MODEL  Curve used for the rectangle's edges: cubic
+                        (default) or bspline[:SEGMENTS] are fitted to all
+                        the points found along each edge, quadratic goes
+                        through one point half way along
+      --mapping MODE    How the output is mapped onto the rectangle: coons
+                        (default) blends all four edges, rows bends each row
+                        to follow the left and right edges only
+      --sampler NAME    nearest, bilinear, bicubic (default) or lanczos3
+      --plain           Write plain (ASCII) PGM/PPM
+      --alpha           Add an alpha channel, transparent where the output
+                        maps outside the input (written as PAM)
+      --debug-svg PATH  Write an SVG showing the detected edges
+  -q, --quiet           Don't print progress information
+  -h, --help            Show this help
+";
+
+// The output width used when neither dimension is given
+const DEFAULT_WIDTH : usize = 4000;
+
+// What to do when the rectangle we found isn't the expected shape,
+// which usually means we've found the wrong thing
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RatioCheck {
+  Off,
+  Warn,
+  Fail,
+}
+
+impl FromStr for RatioCheck {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<RatioCheck, String> {
+    match s {
+      "off"  => Ok(RatioCheck::Off),
+      "warn" => Ok(RatioCheck::Warn),
+      "fail" => Ok(RatioCheck::Fail),
+      _ => Err(format!("Unknown ratio check '{}' (expected off, warn or fail)", s)),
+    }
+  }
+}
+
+// Which way the rectangle is found
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Detector {
+  // box_finder's edge walking
+  Walk,
+  // Hough lines on the edge map
+  Hough,
+  // Walk, falling back to Hough
+  Auto,
+}
+
+impl FromStr for Detector {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Detector, String> {
+    match s {
+      "walk"  => Ok(Detector::Walk),
+      "hough" => Ok(Detector::Hough),
+      "auto"  => Ok(Detector::Auto),
+      _ => Err(format!("Unknown detector '{}' (expected walk, hough or auto)", s)),
+    }
+  }
+}
+
+pub struct Options {
+  pub input: String,
+  pub output: Option<String>,
+  pub width: Option<usize>,
+  pub height: Option<usize>,
+  pub scale: f64,
+  pub auto_size: bool,
+  pub aspect: f64,
+  pub ratio_check: RatioCheck,
+  pub ratio_tolerance: f64,
+  pub detector: Detector,
+  pub finder: FinderOptions,
+  pub denoise: Option<Denoise>,
+  pub detect_enhance: Option<Enhance>,
+  pub detect_edges: Option<EdgeMap>,
+  pub save_edges: Option<String>,
+  pub output_enhance: Option<Enhance>,
+  pub edge_model: EdgeModel,
+  pub mapping: Mapping,
+  pub sampler: Sampler,
+  pub plain: bool,
+  pub alpha: bool,
+  pub debug_svg: Option<String>,
+  pub quiet: bool,
+  pub help: bool,
+}
+
+// Parses "W:H" or a single number
+fn parse_aspect(s: &str) -> Result<f64, String> {
+  let ratio = match s.find(':') {
+    Some(colon) => {
+      let w = parse_number::<f64>("--aspect", &s[..colon])?;
+      let h = parse_number::<f64>("--aspect", &s[colon+1..])?;
+      w / h
+    },
+    None => parse_number::<f64>("--aspect", s)?,
+  };
+  if !ratio.is_finite() || ratio <= 0.0 {
+    return Err(format!("Bad aspect ratio '{}'", s));
+  }
+  Ok(ratio)
+}
+
+fn parse_number<T: FromStr>(option: &str, s: &str) -> Result<T, String> {
+  s.trim().parse::<T>().map_err(|_| format!("Bad value '{}' for {}", s, option))
+}
+
+impl Options {
+  // 'args' excludes the program name
+  pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { input: String::new(), output: None,
+                                width: None, height: None, scale: 1.0, auto_size: false,
+                                aspect: 2.0, ratio_check: RatioCheck::Warn, ratio_tolerance: 0.1,
+                                detector: Detector::Walk, finder: FinderOptions::default(),
+                                denoise: None, detect_enhance: None,
+                                detect_edges: None, save_edges: None, output_enhance: None,
+                                edge_model: EdgeModel::Cubic, mapping: Mapping::Coons,
+                                sampler: Sampler::Bicubic, plain: false, alpha: false,
+                                debug_svg: None, quiet: false, help: false };
+    let mut names = Vec::new();
+    let mut threshold = None;
+
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+      // Allow both --option value and --option=value
+      let (name, mut inline_value) = match arg.find('=') {
+        Some(eq) if arg.starts_with("--") => (&arg[..eq], Some(arg[eq+1..].to_string())),
+        _ => (arg.as_str(), None),
+      };
+      let mut value = || -> Result<String, String> {
+        match inline_value.take() {
+          Some(v) => Ok(v),
+          None => it.next().cloned().ok_or(format!("{} needs a value", name)),
+        }
+      };
+
+      match name {
+        "-h" | "--help"  => options.help = true,
+        "-q" | "--quiet" => options.quiet = true,
+        "--plain"        => options.plain = true,
+        "--alpha"        => options.alpha = true,
+        "--auto-size"    => options.auto_size = true,
+        "-o" | "--output" => options.output = Some(value()?),
+        "-W" | "--width"  => options.width = Some(parse_number(name, &value()?)?),
+        "-H" | "--height" => options.height = Some(parse_number(name, &value()?)?),
+        "-s" | "--scale"  => options.scale = parse_number(name, &value()?)?,
+        "-a" | "--aspect" => options.aspect = parse_aspect(&value()?)?,
+        "--ratio-check"   => options.ratio_check = value()?.parse::<RatioCheck>()?,
+        "--ratio-tolerance" => options.ratio_tolerance = parse_number(name, &value()?)?,
+        "--detector"      => options.detector = value()?.parse::<Detector>()?,
+        "--search"        => options.finder.search = value()?.parse()?,
+        "--threshold"     => threshold = Some(value()?.parse()?),
+        "--detect-edges"  => options.detect_edges = Some(value()?.parse()?),
+        "--save-edges"    => options.save_edges = Some(value()?),
+        "--denoise"       => options.denoise = Some(value()?.parse()?),
+        "--detect-enhance" => options.detect_enhance = Some(value()?.parse()?),
+        "--output-enhance" => options.output_enhance = Some(value()?.parse()?),
+        "--edge-model"    => options.edge_model = value()?.parse()?,
+        "--mapping"       => options.mapping = value()?.parse()?,
+        "--sampler"       => options.sampler = value()?.parse::<Sampler>()?,
+        "--debug-svg"     => options.debug_svg = Some(value()?),
+        _ if name.starts_with('-') && name != "-" => return Err(format!("Unknown option {}", name)),
+        _ => names.push(arg.clone()),
+      }
+    }
+
+    if options.help {
+      return Ok(options);
+    }
+    options.finder.aspect = options.aspect;
+    // An edge map has no noise on the paper for the scan to judge by
+    options.finder.threshold = match (threshold, options.detect_edges) {
+      (Some(t), _) => t,
+      (None, Some(_)) => ThresholdMode::Otsu,
+      (None, None) => ThresholdMode::Scan,
+    };
+    match names.len() {
+      1 => (),
+      2 if options.output.is_none() => options.output = Some(names[1].clone()),
+      0 => return Err(String::from("No input file given")),
+      _ => return Err(String::from("Too many file names given")),
+    }
+    options.input = names[0].clone();
+
+    if options.auto_size && (options.width.is_some() || options.height.is_some()) {
+      return Err(String::from("--auto-size can't be combined with --width or --height"));
+    }
+    if options.width == Some(0) || options.height == Some(0) ||
+       !options.scale.is_finite() || options.scale <= 0.0 {
+      return Err(String::from("Output size must be positive"));
+    }
+    if options.ratio_tolerance.is_nan() || options.ratio_tolerance < 0.0 {
+      return Err(String::from("--ratio-tolerance can't be negative"));
+    }
+
+    Ok(options)
+  }
+
+  // The size of the output image given the measured width and height of
+  // the rectangle; a missing dimension is derived from the other using
+  // the aspect ratio
+  pub fn output_size(&self, measured: (f64, f64)) -> (usize, usize) {
+    let (width, height) = match (self.width, self.height) {
+      _ if self.auto_size => measured,
+      (Some(w), Some(h)) => (w as f64, h as f64),
+      (Some(w), None)    => (w as f64, w as f64 / self.aspect),
+      (None, Some(h))    => (h as f64 * self.aspect, h as f64),
+      (None, None)       => (DEFAULT_WIDTH as f64, DEFAULT_WIDTH as f64 / self.aspect),
+    };
+
+    (((width * self.scale).round() as usize).max(1),
+     ((height * self.scale).round() as usize).max(1))
+  }
+
+  // Compare the measured aspect ratio of the rectangle against the
+  // expected one, returning a description of the problem if it's out
+  // by more than the tolerance
+  pub fn check_ratio(&self, measured: f64) -> Result<(), String> {
+    let error = measured / self.aspect - 1.0;
+    if self.ratio_check == RatioCheck::Off || error.abs() <= self.ratio_tolerance {
+      return Ok(());
+    }
+    Err(format!("measured aspect ratio {:.3} is {:.1}% away from the expected {:.3}",
+                measured, error * 100.0, self.aspect))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<Options, String> {
+    Options::parse(&args.iter().map(|a| a.to_string()).collect::<Vec<_>>())
+  }
+
+  #[test]
+  fn inline_and_separate_values() {
+    for args in [&["--width=300", "--sampler=nearest", "in.pgm"][..],
+                 &["--width", "300", "--sampler", "nearest", "in.pgm"][..]] {
+      let options = parse(args).unwrap();
+      assert_eq!(options.width, Some(300));
+      assert!(matches!(options.sampler, Sampler::Nearest));
+      assert_eq!(options.input, "in.pgm");
+    }
+    let options = parse(&["-o", "a.pgm", "in.pgm"]).unwrap();
+    assert_eq!(options.output.as_deref(), Some("a.pgm"));
+    let options = parse(&["-", "-"]).unwrap();
+    assert_eq!((options.input.as_str(), options.output.as_deref()), ("-", Some("-")));
+  }
+
+  #[test]
+  fn missing_value() {
+    assert_eq!(parse(&["in.pgm", "--width"]).err(), Some(String::from("--width needs a value")));
+    assert!(parse(&["--width=", "in.pgm"]).is_err());
+    assert!(parse(&["--width=wide", "in.pgm"]).is_err());
+  }
+
+  #[test]
+  fn size_and_scale() {
+    let options = parse(&["-W", "300", "-s", "2", "in.pgm"]).unwrap();
+    assert_eq!(options.output_size((1.0, 1.0)), (600, 300));
+    let options = parse(&["-H", "100", "-a", "3:2", "in.pgm"]).unwrap();
+    assert_eq!(options.output_size((1.0, 1.0)), (150, 100));
+    let options = parse(&["--auto-size", "--scale=0.5", "in.pgm"]).unwrap();
+    assert_eq!(options.output_size((201.0, 99.0)), (101, 50));
+
+    assert!(parse(&["--auto-size", "--width=300", "in.pgm"]).is_err());
+    assert!(parse(&["--scale=0", "in.pgm"]).is_err());
+    assert!(parse(&["--height=0", "in.pgm"]).is_err());
+  }
+
+  #[test]
+  fn aspect_and_ratio_check() {
+    for bad in ["0:1", "1:0", "-2", "2:", "square"] {
+      assert!(parse(&["--aspect", bad, "in.pgm"]).is_err(), "aspect {}", bad);
+    }
+    assert!(parse(&["--ratio-check=sometimes", "in.pgm"]).is_err());
+    assert!(parse(&["--ratio-tolerance=-0.1", "in.pgm"]).is_err());
+
+    let options = parse(&["--aspect=1:1", "in.pgm"]).unwrap();
+    assert!(options.check_ratio(1.05).is_ok());
+    assert!(options.check_ratio(1.2).is_err());
+    let options = parse(&["--aspect=1:1", "--ratio-check", "off", "in.pgm"]).unwrap();
+    assert!(options.check_ratio(1.2).is_ok());
+  }
+
+  #[test]
+  fn unknown_option_and_file_names() {
+    assert_eq!(parse(&["--frobnicate", "in.pgm"]).err(),
+               Some(String::from("Unknown option --frobnicate")));
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["a.pgm", "b.pgm", "c.pgm"]).is_err());
+    assert!(parse(&["--help", "--frobnicate"]).is_err());
+    assert!(parse(&["--help"]).unwrap().help);
+  }
+}
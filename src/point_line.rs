@@ -1,5 +1,5 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
 
 use std::f64;
 
@@ -35,43 +35,25 @@ pub enum Direction {
 }
 
 pub type Line = (Point, Point);
+pub type Linef = (Pointf, Pointf);
 
 impl Point {
   pub fn step(&mut self, d: Direction, i: &Image, amount: usize) -> bool {
-    let tomod;
-    let limit;
-    let inc : i8;
-    match d {
-      Direction::Down => {
-          tomod = &mut self.y;
-          limit = i.get_size().y;
-          inc   = 1
-        },
-      Direction::Up => {
-          tomod = &mut self.y;
-          limit = 0;
-          inc   = -1
-        },
-      Direction::Left => {
-          tomod = &mut self.x;
-          limit = 0;
-          inc   = -1
-        },
-      Direction::Right => {
-          tomod = &mut self.x;
-          limit = i.get_size().x;
-          inc   = 1
-        },
-    }
+    let (tomod, limit, inc) : (&mut usize, usize, i8) = match d {
+      Direction::Down  => (&mut self.y, i.get_size().y, 1),
+      Direction::Up    => (&mut self.y, 0, -1),
+      Direction::Left  => (&mut self.x, 0, -1),
+      Direction::Right => (&mut self.x, i.get_size().x, 1),
+    };
     
     if (inc > 0 && (amount >= limit || *tomod >= limit-amount)) ||
        (inc < 0 && *tomod < amount) {
       return false;
     }
     if inc < 0 {
-      *tomod = *tomod - amount;
+      *tomod -= amount;
     } else {
-      *tomod = *tomod + amount;
+      *tomod += amount;
     }
 
     true
@@ -98,6 +80,37 @@ impl Point {
   }
 }
 
+impl Pointf {
+  pub fn distance(&self, other: &Pointf) -> f64 {
+    ((self.x - other.x).powi(2) + (self.y - other.y).powi(2)).sqrt()
+  }
+
+  // The point 'amount' away in direction 'd'
+  pub fn offset(&self, d: Direction, amount: f64) -> Pointf {
+    match d {
+      Direction::Down  => Pointf { x: self.x, y: self.y + amount },
+      Direction::Up    => Pointf { x: self.x, y: self.y - amount },
+      Direction::Left  => Pointf { x: self.x - amount, y: self.y },
+      Direction::Right => Pointf { x: self.x + amount, y: self.y },
+    }
+  }
+
+  // The point 'amount' away along the unit vector 'dir'
+  pub fn along(&self, dir: Pointf, amount: f64) -> Pointf {
+    Pointf { x: self.x + dir.x * amount, y: self.y + dir.y * amount }
+  }
+
+  // Unit vector from this point towards 'other', None if they're the same
+  pub fn unit_to(&self, other: &Pointf) -> Option<Pointf> {
+    let len = self.distance(other);
+    if len == 0.0 {
+      return None;
+    }
+    Some(Pointf { x: (other.x - self.x) / len, y: (other.y - self.y) / len })
+  }
+}
+
+
 impl Direction {
   pub fn clockwise(self) -> Direction {
     match self {
@@ -115,32 +128,8 @@ impl Direction {
       Direction::Left => Direction::Down,
     }
   }
-} 
-
-pub fn line_intersection(l1 : &Line, l2 : &Line) -> Point {
-  let (l1p1, l1p2) = *l1;
-  let (l2p1, l2p2) = *l2;
-  let l1p1x = l1p1.x as f64;
-  let l1p1y = l1p1.y as f64;
-  let l1p2x = l1p2.x as f64;
-  let l1p2y = l1p2.y as f64;
-  let l2p1x = l2p1.x as f64;
-  let l2p1y = l2p1.y as f64;
-  let l2p2x = l2p2.x as f64;
-  let l2p2y = l2p2.y as f64;
-
-  // From https://en.wikipedia.org/wiki/Line%E2%80%93line_intersection#Given_two_points_on_each_line
-  let xnumer = (l1p1x * l1p2y - l1p1y * l1p2x) * (l2p1x - l2p2x) -
-               (l1p1x - l1p2x) * (l2p1x * l2p2y - l2p1y * l2p2x);
-  let denom = (l1p1x - l1p2x) * (l2p1y - l2p2y) -
-              (l1p1y - l1p2y) * (l2p1x - l2p2x);
-
-  let ynumer = (l1p1x * l1p2y - l1p1y * l1p2x) * (l2p1y - l2p2y) -
-               (l1p1y - l1p2y) * (l2p1x * l2p2y - l2p1y * l2p2x);
-
-  let x = xnumer / denom;
-  let y = ynumer / denom;
-
-  // TODO: We should return this as an f64 point
-  Point { x: x as usize, y: y as usize }
+  // A unit vector pointing in this direction
+  pub fn unit(self) -> Pointf {
+    Pointf { x: 0.0, y: 0.0 }.offset(self, 1.0)
+  }
 }
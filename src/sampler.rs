@@ -0,0 +1,177 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Resampling of an image at fractional locations
+// Pixel (x,y) is taken to be centred on the integer location (x,y), and
+// anything off the edge of the image takes the value of the nearest edge pixel
+
+use std::f64;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use image::Image;
+use point_line::{Point, Pointf};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Sampler {
+  Nearest,
+  Bilinear,
+  Bicubic,
+  Lanczos3,
+}
+
+impl FromStr for Sampler {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Sampler, String> {
+    match s {
+      "nearest"  => Ok(Sampler::Nearest),
+      "bilinear" => Ok(Sampler::Bilinear),
+      "bicubic"  => Ok(Sampler::Bicubic),
+      "lanczos3" => Ok(Sampler::Lanczos3),
+      _ => Err(format!("Unknown sampler '{}' (expected nearest, bilinear, bicubic or lanczos3)", s)),
+    }
+  }
+}
+
+// Clamp a (possibly negative) coordinate to the range 0..limit
+fn clamp_coord(c: isize, limit: usize) -> usize {
+  if c < 0 {
+    0
+  } else if c as usize >= limit {
+    limit - 1
+  } else {
+    c as usize
+  }
+}
+
+// Linear interpolation (tent) kernel
+fn triangle(x: f64) -> f64 {
+  let x = x.abs();
+  if x < 1.0 { 1.0 - x } else { 0.0 }
+}
+
+// Keys cubic convolution kernel with a=-0.5 (Catmull-Rom)
+fn cubic(x: f64) -> f64 {
+  let a = -0.5;
+  let x = x.abs();
+  if x < 1.0 {
+    ((a + 2.0) * x - (a + 3.0)) * x * x + 1.0
+  } else if x < 2.0 {
+    ((a * x - 5.0 * a) * x + 8.0 * a) * x - 4.0 * a
+  } else {
+    0.0
+  }
+}
+
+fn sinc(x: f64) -> f64 {
+  if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+fn lanczos3(x: f64) -> f64 {
+  if x.abs() < 3.0 { sinc(x) * sinc(x / 3.0) } else { 0.0 }
+}
+
+// Convolve the area around 'p' with a separable kernel that is non-zero
+// for |x| < radius.  The weights are normalised so that they sum to 1,
+// which keeps flat areas flat even for the Lanczos kernel
+fn convolve(i: &Image, p: Pointf, c: usize, radius: isize, kernel: fn(f64) -> f64) -> f64 {
+  let size = i.get_size();
+  let base_x = p.x.floor() as isize;
+  let base_y = p.y.floor() as isize;
+
+  let mut total = 0.0;
+  let mut total_weight = 0.0;
+  for ky in (1 - radius)..(radius + 1) {
+    let sy = base_y + ky;
+    let wy = kernel(p.y - sy as f64);
+    if wy == 0.0 { continue; }
+    let y = clamp_coord(sy, size.y);
+
+    for kx in (1 - radius)..(radius + 1) {
+      let sx = base_x + kx;
+      let w = wy * kernel(p.x - sx as f64);
+      if w == 0.0 { continue; }
+      let x = clamp_coord(sx, size.x);
+
+      total += w * i.get(Point { x, y }, c) as f64;
+      total_weight += w;
+    }
+  }
+
+  total / total_weight
+}
+
+impl Sampler {
+  // Return the value of channel 'c' of the image at the fractional point 'p'
+  pub fn sample(self, i: &Image, p: Pointf, c: usize) -> u16 {
+    let size = i.get_size();
+    let value = match self {
+      Sampler::Nearest => {
+        let x = clamp_coord(p.x.round() as isize, size.x);
+        let y = clamp_coord(p.y.round() as isize, size.y);
+        return i.get(Point { x, y }, c);
+      },
+      Sampler::Bilinear => convolve(i, p, c, 1, triangle),
+      Sampler::Bicubic  => convolve(i, p, c, 2, cubic),
+      Sampler::Lanczos3 => convolve(i, p, c, 3, lanczos3),
+    };
+
+    // The cubic and Lanczos kernels have negative lobes so can overshoot
+    value.round().clamp(0.0, i.get_maxval() as f64) as u16
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::ColourType;
+
+  const SAMPLERS : [Sampler; 4] = [Sampler::Nearest, Sampler::Bilinear, Sampler::Bicubic, Sampler::Lanczos3];
+
+  // An RGB image with no two neighbouring values alike
+  fn scrambled() -> Image {
+    let mut i = Image::new(7, 5, ColourType::Rgb, 1000);
+    for y in 0..5 {
+      for x in 0..7 {
+        for c in 0..3 {
+          i.set(Point { x, y }, c, ((x * 397 + y * 211 + c * 89) % 1001) as u16);
+        }
+      }
+    }
+    i
+  }
+
+  #[test]
+  fn exact_at_pixel_centres() {
+    let i = scrambled();
+    for sampler in SAMPLERS {
+      for y in 0..5 {
+        for x in 0..7 {
+          let p = Pointf { x: x as f64, y: y as f64 };
+          for c in 0..3 {
+            assert_eq!(sampler.sample(&i, p, c), i.get(Point { x, y }, c),
+                       "{:?} at {},{} channel {}", sampler, x, y, c);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn bilinear_midpoints() {
+    let i = scrambled();
+    let at = |x: usize, y: usize| i.get(Point { x, y }, 1) as f64;
+    for y in 0..4 {
+      for x in 0..6 {
+        let across = Sampler::Bilinear.sample(&i, Pointf { x: x as f64 + 0.5, y: y as f64 }, 1);
+        assert_eq!(across, ((at(x, y) + at(x + 1, y)) / 2.0).round() as u16);
+        let down = Sampler::Bilinear.sample(&i, Pointf { x: x as f64, y: y as f64 + 0.5 }, 1);
+        assert_eq!(down, ((at(x, y) + at(x, y + 1)) / 2.0).round() as u16);
+        let centre = Sampler::Bilinear.sample(&i, Pointf { x: x as f64 + 0.5, y: y as f64 + 0.5 }, 1);
+        let mean = (at(x, y) + at(x + 1, y) + at(x, y + 1) + at(x + 1, y + 1)) / 4.0;
+        assert_eq!(centre, mean.round() as u16);
+      }
+    }
+  }
+}
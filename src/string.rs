@@ -1,5 +1,5 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
 
 use std::convert;
 use std::iter;
@@ -29,12 +29,12 @@ pub fn skip_whitespace<E, I>(it: &mut iter::Peekable<I>) -> Result<(), E>
             in_comment = false;
           }
         },
-        // On io::error, drop through and the it.next() try!
+        // On io::error, drop through and the it.next()?
         // will propagate the err
         Some(&Err(_)) => { },
       }
     }
-    try!(it.next().expect("skip_whitespace consume"));
+    it.next().expect("skip_whitespace consume")?;
   }
  
   Ok(())
@@ -54,23 +54,23 @@ pub fn read_integer<I,E>(it: &mut iter::Peekable<I>) -> Result<usize, ImageErr>
         None => { break ; },
         Some(&Ok(b)) => {
           let ch = b as char;
-          if ch.is_digit(10) {
+          if ch.is_ascii_digit() {
             have_digit = true;
-            result = result * 10 + (b - ('0' as u8)) as usize;
+            result = result * 10 + (b - b'0') as usize;
           } else {
-            if ch.is_whitespace() {
+            if ch.is_whitespace() || ch == '#' {
               break;
             }
-            // A number not terminated by a whitespace is an error
+            // A number not terminated by a whitespace or comment is an error
             return Err(ImageErr::NumErr);
           }
         },
-        // On io::error, drop through and the it.next() try!
+        // On io::error, drop through and the it.next()?
         // will propagate the err
         Some(&Err(_)) => { },
       }
     }
-    try!(it.next().expect("read_integer consume"));
+    it.next().expect("read_integer consume")?;
   }
 
   if have_digit {
@@ -79,3 +79,36 @@ pub fn read_integer<I,E>(it: &mut iter::Peekable<I>) -> Result<usize, ImageErr>
     Err(ImageErr::NumErr)
   }
 }
+
+/// Reads a word (anything up to the next whitespace) from the stream
+pub fn read_word<I,E>(it: &mut iter::Peekable<I>) -> Result<String, ImageErr>
+  where I: Iterator<Item=Result<u8, E>>,
+        ImageErr: convert::From<E> {
+  let mut result = String::new();
+
+  loop {
+    {
+      let cur = it.peek();
+      match cur {
+        None => { break ; },
+        Some(&Ok(b)) => {
+          let ch = b as char;
+          if ch.is_whitespace() {
+            break;
+          }
+          result.push(ch);
+        },
+        // On io::error, drop through and the it.next()?
+        // will propagate the err
+        Some(&Err(_)) => { },
+      }
+    }
+    it.next().expect("read_word consume")?;
+  }
+
+  if result.is_empty() {
+    Err(ImageErr::BadHeader(String::from("Expected a word")))
+  } else {
+    Ok(result)
+  }
+}
@@ -0,0 +1,132 @@
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+// Choosing the level that separates dark from light
+
+use image::*;
+use point_line::Point;
+
+// Otsu's method: the level that best splits the histogram into two
+// classes, maximising the variance between them.  Values <= the result
+// are dark.
+// https://en.wikipedia.org/wiki/Otsu%27s_method
+pub fn otsu(histogram: &[usize]) -> u16 {
+  let total = histogram.iter().sum::<usize>() as f64;
+  let sum_all = histogram.iter().enumerate().map(|(v, &n)| v as f64 * n as f64).sum::<f64>();
+
+  let mut best = 0;
+  let mut best_variance = -1.0;
+  let mut count_dark = 0.0;
+  let mut sum_dark = 0.0;
+  for (v, &n) in histogram.iter().enumerate() {
+    count_dark += n as f64;
+    sum_dark += v as f64 * n as f64;
+    let count_light = total - count_dark;
+    if count_dark == 0.0 { continue; }
+    if count_light == 0.0 { break; }
+
+    let mean_dark = sum_dark / count_dark;
+    let mean_light = (sum_all - sum_dark) / count_light;
+    let variance = count_dark * count_light * (mean_dark - mean_light).powi(2);
+    if variance > best_variance {
+      best_variance = variance;
+      best = v;
+    }
+  }
+  best as u16
+}
+
+// Sauvola's adaptive threshold, for lighting that varies across the image.
+// Each pixel's threshold is m * (1 + k * (s/R - 1)) where m and s are the
+// mean and standard deviation over a 'window' sized square around it and R
+// is half the range.  Rather than a black and white image this gives a grey
+// one shifted so that each pixel's threshold ends up at maxval/2, which
+// keeps the shading across a line for finding its centre.
+// Only the first channel of 'i' is used.
+pub fn sauvola(i: &Image, window: usize, k: f64) -> Image {
+  let size = i.get_size();
+  let maxval = i.get_maxval();
+  let range = maxval as f64 / 2.0;
+
+  // Integral images of the values and their squares; entry (x,y) is the
+  // sum over everything above and to the left of pixel (x,y)
+  let stride = size.x + 1;
+  let mut sum = vec![0u64; stride * (size.y + 1)];
+  let mut sum_sq = vec![0u64; stride * (size.y + 1)];
+  for y in 0..size.y {
+    let mut row = 0u64;
+    let mut row_sq = 0u64;
+    for x in 0..size.x {
+      let v = i.get(Point { x, y }, 0) as u64;
+      row += v;
+      row_sq += v * v;
+      sum[(y + 1) * stride + x + 1] = sum[y * stride + x + 1] + row;
+      sum_sq[(y + 1) * stride + x + 1] = sum_sq[y * stride + x + 1] + row_sq;
+    }
+  }
+  let area_sum = |table: &[u64], x0: usize, y0: usize, x1: usize, y1: usize| {
+    (table[y1 * stride + x1] + table[y0 * stride + x0]) as f64 -
+    (table[y0 * stride + x1] + table[y1 * stride + x0]) as f64
+  };
+
+  let half = window / 2;
+  let mut result = Image::new(size.x, size.y, ColourType::Grey, maxval);
+  for y in 0..size.y {
+    let y0 = y.saturating_sub(half);
+    let y1 = (y + half + 1).min(size.y);
+    for x in 0..size.x {
+      let x0 = x.saturating_sub(half);
+      let x1 = (x + half + 1).min(size.x);
+      let n = ((x1 - x0) * (y1 - y0)) as f64;
+
+      let mean = area_sum(&sum, x0, y0, x1, y1) / n;
+      let variance = (area_sum(&sum_sq, x0, y0, x1, y1) / n - mean * mean).max(0.0);
+      let threshold = mean * (1.0 + k * (variance.sqrt() / range - 1.0));
+
+      let p = Point { x, y };
+      let shifted = i.get(p, 0) as f64 - threshold + range;
+      result.set(p, 0, shifted.round().clamp(0.0, maxval as f64) as u16);
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn otsu_splits_two_levels() {
+    let mut histogram = vec![0; 256];
+    histogram[60] = 300;
+    histogram[61] = 200;
+    histogram[190] = 400;
+    histogram[192] = 100;
+    let level = otsu(&histogram);
+    assert!((61..190).contains(&level), "level {}", level);
+  }
+
+  #[test]
+  fn sauvola_follows_gradient() {
+    // Dark bars on a background that gets much lighter to the right; the
+    // bars at the right are lighter than the background at the left
+    let (width, height) = (120, 40);
+    let bar = |x: usize, y: usize| x % 20 < 3 || (15..18).contains(&y);
+    let mut i = Image::new(width, height, ColourType::Grey, 255);
+    for y in 0..height {
+      for x in 0..width {
+        let background = 60.0 + 190.0 * x as f64 / width as f64;
+        let v = if bar(x, y) { background * 0.4 } else { background };
+        i.set(Point { x, y }, 0, v as u16);
+      }
+    }
+
+    let mask = sauvola(&i, 15, 0.2);
+    for y in 0..height {
+      for x in 0..width {
+        let light = mask.get(Point { x, y }, 0) > 255 / 2;
+        assert_eq!(light, !bar(x, y), "at {},{}", x, y);
+      }
+    }
+  }
+}
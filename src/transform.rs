@@ -1,14 +1,69 @@
-/// (c) David Alan Gilbert <dave@treblig.org> 2016
-/// Licensed under GPLv3, see the LICENSE file for a full copy
+// (c) David Alan Gilbert <dave@treblig.org> 2016
+// Licensed under GPLv3, see the LICENSE file for a full copy
+
+use std::str::FromStr;
 
 use curve;
+use curve::Curve;
 use image;
-use point_line::Point;
+use point_line::{Point, Pointf};
+use sampler::Sampler;
+
+// How each output pixel is mapped back into the input
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Mapping {
+  // Each row is a curve through the left, middle and right curves, so
+  // only the vertical curves bend it (transform)
+  Rows,
+  // Straight from the Coons patch of all four edges (transform_patch)
+  Coons,
+}
+
+impl FromStr for Mapping {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Mapping, String> {
+    match s {
+      "rows"  => Ok(Mapping::Rows),
+      "coons" => Ok(Mapping::Coons),
+      _ => Err(format!("Unknown mapping '{}' (expected rows or coons)", s)),
+    }
+  }
+}
+
+// Is 'p' within the area covered by the pixels of 'i'?
+fn inside(i: &image::Image, p: Pointf) -> bool {
+  let size = i.get_size();
+  p.x >= -0.5 && p.y >= -0.5 &&
+    p.x < size.x as f64 - 0.5 && p.y < size.y as f64 - 0.5
+}
+
+// Set the output pixel 'p' from the input at 'source'.
+// If the output has an alpha channel it's set transparent wherever the
+// source point is outside the input image, otherwise it's taken from
+// the input's alpha (or opaque if the input has none)
+fn set_from(i: &image::Image, o: &mut image::Image, p: Point, source: Pointf, sampler: Sampler) {
+  for c in 0..i.get_colour().colour_channels() {
+    o.set(p, c, sampler.sample(i, source, c));
+  }
+
+  if let Some(out_alpha) = o.get_colour().alpha_channel() {
+    let alpha = if !inside(i, source) {
+      0
+    } else if let Some(in_alpha) = i.get_colour().alpha_channel() {
+      sampler.sample(i, source, in_alpha)
+    } else {
+      o.get_maxval()
+    };
+    o.set(p, out_alpha, alpha);
+  }
+}
 
 pub fn transform(i: &image::Image, o: &mut image::Image,
-                 lcurve: &curve::Bezierq,
-                 mcurve: &curve::Bezierq,
-                 rcurve: &curve::Bezierq) {
+                 lcurve: &dyn Curve,
+                 mcurve: &dyn Curve,
+                 rcurve: &dyn Curve,
+                 sampler: Sampler) {
   let out_size = o.get_size();
   for y in 0..out_size.y {
     let t_y = y as f64 / (out_size.y as f64);
@@ -19,14 +74,90 @@ pub fn transform(i: &image::Image, o: &mut image::Image,
     // For each horizontal line we create a bezier through the source
     let line_bez = curve::Bezierq::through(left_point, mid_point, right_point, 0.5);
 
-    //println!("transform: y={} t_y={} {:?}/{:?}/{:?}", y, t_y, left_point, mid_point, right_point);
+    //eprintln!("transform: y={} t_y={} {:?}/{:?}/{:?}", y, t_y, left_point, mid_point, right_point);
     for x in 0..out_size.x {
       let t_x = x as f64 / (out_size.x as f64);
-      let cur_point = Point::from(line_bez.interp(t_x));
+      set_from(i, o, Point { x, y }, line_bez.interp(t_x), sampler);
+    }
+  }
+}
+
+// Map every output pixel through 'patch', so the top and bottom edges'
+// bowing is followed as well as the sides'
+pub fn transform_patch(i: &image::Image, o: &mut image::Image,
+                       patch: &curve::Coons,
+                       sampler: Sampler) {
+  let out_size = o.get_size();
+  // The points on the top and bottom are the same for every row
+  let t_xs : Vec<f64> = (0..out_size.x).map(|x| x as f64 / (out_size.x as f64)).collect();
+  let top_bottoms : Vec<(Pointf, Pointf)> = t_xs.iter().map(|&t| patch.top_bottom(t)).collect();
+
+  for y in 0..out_size.y {
+    let t_y = y as f64 / (out_size.y as f64);
+    let (left_point, right_point) = patch.left_right(t_y);
+    for (x, (&t_x, &(top_point, bottom_point))) in t_xs.iter().zip(top_bottoms.iter()).enumerate() {
+      let source = patch.blend(t_x, t_y, top_point, bottom_point, left_point, right_point);
+      set_from(i, o, Point { x, y }, source, sampler);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::{ColourType, Image};
+
+  fn straight(a: Pointf, b: Pointf) -> curve::Bezierq {
+    curve::Bezierq::through(a, Pointf { x: (a.x + b.x) / 2.0, y: (a.y + b.y) / 2.0 }, b, 0.5)
+  }
+
+  fn numbered(width: usize, height: usize) -> Image {
+    let mut i = Image::new(width, height, ColourType::Grey, 255);
+    for y in 0..height {
+      for x in 0..width {
+        i[Point { x, y }] = (y * width + x) as u16;
+      }
+    }
+    i
+  }
+
+  #[test]
+  fn patch_of_the_whole_image_copies_it() {
+    let i = numbered(6, 4);
+    let corner = |x, y| Pointf { x, y };
+    let top = straight(corner(0.0, 0.0), corner(6.0, 0.0));
+    let bottom = straight(corner(0.0, 4.0), corner(6.0, 4.0));
+    let left = straight(corner(0.0, 0.0), corner(0.0, 4.0));
+    let right = straight(corner(6.0, 0.0), corner(6.0, 4.0));
+    let patch = curve::Coons::new(&top, &bottom, &left, &right);
+
+    let mut o = Image::new(6, 4, ColourType::Grey, 255);
+    transform_patch(&i, &mut o, &patch, Sampler::Nearest);
+    for y in 0..4 {
+      for x in 0..6 {
+        assert_eq!(o[Point { x, y }], i[Point { x, y }]);
+      }
+    }
+  }
+
+  #[test]
+  fn patch_of_half_the_image_scales_it() {
+    // The right half of the top two rows, doubled in size
+    let i = numbered(8, 4);
+    let corner = |x, y| Pointf { x, y };
+    let top = straight(corner(4.0, 0.0), corner(8.0, 0.0));
+    let bottom = straight(corner(4.0, 2.0), corner(8.0, 2.0));
+    let left = straight(corner(4.0, 0.0), corner(4.0, 2.0));
+    let right = straight(corner(8.0, 0.0), corner(8.0, 2.0));
+    let patch = curve::Coons::new(&top, &bottom, &left, &right);
 
-      // TODO: Use some type of interpolation based on the fractional location
-      // to get an average from neighbours
-      o[Point { x: x, y: y }] = i[cur_point];
+    let mut o = Image::new(8, 4, ColourType::Grey, 255);
+    transform_patch(&i, &mut o, &patch, Sampler::Nearest);
+    for y in 0..4 {
+      for x in 0..8 {
+        let source = Point { x: 4 + (x as f64 / 2.0).round() as usize, y: (y as f64 / 2.0).round() as usize };
+        assert_eq!(o[Point { x, y }], i[Point { x: source.x.min(7), y: source.y }]);
+      }
     }
   }
 }